@@ -1,4 +1,6 @@
-use crate::simple_value::StringType;
+use serde::ser::{Serialize, Serializer};
+
+use crate::simple_value::{LabelFormat, StringType};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SimpleType(pub StringType);
@@ -19,19 +21,66 @@ impl From<StringType> for CompactKey {
     }
 }
 
+impl Serialize for SimpleType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct("SimpleType", &self.0)
+    }
+}
+
+impl SimpleType {
+    // with omit_matching_lang, the language of a monolingual text (its only type)
+    // is not displayed when it is the most preferred language
+    pub fn is_omitted_lang(types: &[SimpleType], fmt: &LabelFormat) -> bool {
+        match (types, fmt.preferred_lang()) {
+            ([SimpleType(lang)], Some(preferred)) => {
+                fmt.omit_matching_lang && lang.is_labelled(preferred)
+            }
+            _ => false,
+        }
+    }
+}
+
 impl CompactKey {
-    pub fn choose_lang(self, langs: &[String]) -> String {
+    // merge consecutive repetitions of the same type, e.g. "key [Z8, Z8]" into "key [Z8]",
+    // the types are kept in order, from the outer most to the inner most
+    pub fn dedup_types(self) -> Self {
+        match self {
+            CompactKey::StringType(key, mut types) => {
+                types.dedup();
+                CompactKey::StringType(key, types)
+            }
+            CompactKey::Transient(mut types) => {
+                types.dedup();
+                CompactKey::Transient(types)
+            }
+        }
+    }
+
+    // the label of the key, formatted with the template of the (outer most) type of its value, if any
+    fn format_key(key: StringType, types: &[SimpleType], fmt: &LabelFormat) -> String {
+        let label = key.choose_lang(fmt);
+        match types.first().and_then(|SimpleType(t)| {
+            fmt.type_formats
+                .iter()
+                .find(|(zid, _template)| t.is_labelled(zid))
+        }) {
+            Some((_zid, template)) => template.replace("{}", &label),
+            None => label,
+        }
+    }
+
+    pub fn choose_lang(self, fmt: &LabelFormat) -> String {
         match self {
             CompactKey::StringType(key, types) => {
-                if types.is_empty() {
-                    key.choose_lang(langs)
+                if types.is_empty() || SimpleType::is_omitted_lang(&types, fmt) {
+                    key.choose_lang(fmt)
                 } else {
                     format!(
                         "{} [{}]",
-                        key.choose_lang(langs),
+                        CompactKey::format_key(key, &types, fmt),
                         types
                             .into_iter()
-                            .map(|t| t.0.choose_lang(langs))
+                            .map(|t| t.0.choose_lang(fmt))
                             .collect::<Vec<String>>()
                             .join(", "),
                     )
@@ -41,7 +90,7 @@ impl CompactKey {
                 "[{}]",
                 types
                     .into_iter()
-                    .map(|t| t.0.choose_lang(langs))
+                    .map(|t| t.0.choose_lang(fmt))
                     .collect::<Vec<String>>()
                     .join(", "),
             ),
@@ -1,10 +1,13 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 
 use serde_json::Value;
 
 use crate::compact_key::{CompactKey, SimpleType};
 use crate::intermediate_form::{IntermediateForm, IntermediateType};
-use crate::simple_value::{SimpleValue, StringType};
+use crate::simple_value::{LabelFormat, SimpleValue, StringType};
+
+// subtrees smaller than this, in number of nodes, are not worth replacing by a $ref
+const MIN_SHARED_SIZE: usize = 4;
 
 // CompactValue is the final type, ready to be converted back to json Value
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -18,6 +21,7 @@ impl From<SimpleValue> for CompactValue {
     fn from(val: SimpleValue) -> Self {
         match val {
             SimpleValue::StringType(k) => CompactValue::KeyType(k.into()),
+            SimpleValue::Scalar(text) => CompactValue::KeyType(StringType::String(text).into()),
             SimpleValue::Array(a) => CompactValue::Array(a.into_iter().map(|x| x.into()).collect()),
             SimpleValue::Object(o) => {
                 CompactValue::Object(o.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
@@ -167,17 +171,242 @@ impl CompactValue {
         }
     }
 
-    pub fn choose_lang(self, langs: &Vec<String>) -> Value {
+    // cleanup after compress_simple_classes(), nested collapses can repeat the same type on a key
+    pub fn dedup_key_types(self) -> Self {
+        match self {
+            CompactValue::KeyType(_) => self,
+            CompactValue::Array(arr) => {
+                Self::Array(arr.into_iter().map(|x| x.dedup_key_types()).collect())
+            }
+            CompactValue::Object(obj) => Self::Object(
+                obj.into_iter()
+                    .map(|(k, v)| (k.dedup_types(), v.dedup_key_types()))
+                    .collect(),
+            ),
+        }
+    }
+
+    // Replace arrays with only one element by the element itself.
+    // This is lossy: the output can no longer be converted back into the original ZObject
+    pub fn collapse_singletons(self) -> Self {
         match self {
-            CompactValue::KeyType(k) => k.choose_lang(langs).into(),
+            CompactValue::KeyType(_) => self,
+            CompactValue::Array(mut arr) => {
+                if arr.len() == 1 {
+                    arr.pop().unwrap().collapse_singletons()
+                } else {
+                    Self::Array(arr.into_iter().map(|x| x.collapse_singletons()).collect())
+                }
+            }
+            CompactValue::Object(obj) => Self::Object(
+                obj.into_iter()
+                    .map(|(k, v)| (k, v.collapse_singletons()))
+                    .collect(),
+            ),
+        }
+    }
+
+    // number of nodes in the subtree, the keys of objects are not counted
+    fn size(&self) -> usize {
+        match self {
+            CompactValue::KeyType(_) => 1,
+            CompactValue::Array(v) => 1 + v.iter().map(|x| x.size()).sum::<usize>(),
+            CompactValue::Object(o) => 1 + o.iter().map(|(_k, v)| v.size()).sum::<usize>(),
+        }
+    }
+
+    fn children(&self) -> Box<dyn Iterator<Item = &CompactValue> + '_> {
+        match self {
+            CompactValue::KeyType(_) => Box::new(std::iter::empty()),
+            CompactValue::Array(v) => Box::new(v.iter()),
+            CompactValue::Object(o) => Box::new(o.iter().map(|(_k, v)| v)),
+        }
+    }
+
+    // occurrences of each subtree (objects and arrays big enough) in the whole tree
+    fn count_subtrees<'a>(&'a self, counts: &mut HashMap<&'a CompactValue, usize>) {
+        if self.size() >= MIN_SHARED_SIZE {
+            *counts.entry(self).or_default() += 1;
+        }
+        self.children().for_each(|x| x.count_subtrees(counts));
+    }
+
+    // occurrences of the repeated subtrees once they are hoisted:
+    // a subtree only inside a hoisted subtree is then found only once, in its definition
+    fn count_hoisted<'a>(
+        &'a self,
+        counts: &HashMap<&'a CompactValue, usize>,
+        hoisted: &mut HashMap<&'a CompactValue, usize>,
+    ) {
+        if counts.get(self).is_some_and(|n| *n >= 2) {
+            let n = hoisted.entry(self).or_default();
+            *n += 1;
+            if *n > 1 {
+                return;
+            }
+        }
+        self.children()
+            .for_each(|x| x.count_hoisted(counts, hoisted));
+    }
+
+    // the subtree with its repeated subtrees replaced by {"$ref": "#/$defs/n"},
+    // their definitions being added to defs, by order of first occurrence
+    fn replace_shared(
+        &self,
+        hoisted: &HashMap<&CompactValue, usize>,
+        ids: &mut HashMap<CompactValue, usize>,
+        defs: &mut Vec<Option<CompactValue>>,
+    ) -> CompactValue {
+        if hoisted.get(self).is_some_and(|n| *n >= 2) {
+            let id = match ids.get(self) {
+                Some(id) => *id,
+                None => {
+                    let id = defs.len();
+                    ids.insert(self.clone(), id);
+                    defs.push(None);
+                    defs[id] = Some(self.replace_children(hoisted, ids, defs));
+                    id
+                }
+            };
+            return CompactValue::Object(BTreeSet::from([(
+                CompactKey::from(StringType::String("$ref".to_string())),
+                CompactValue::KeyType(CompactKey::from(StringType::String(format!(
+                    "#/$defs/{}",
+                    id + 1
+                )))),
+            )]));
+        }
+        self.replace_children(hoisted, ids, defs)
+    }
+
+    fn replace_children(
+        &self,
+        hoisted: &HashMap<&CompactValue, usize>,
+        ids: &mut HashMap<CompactValue, usize>,
+        defs: &mut Vec<Option<CompactValue>>,
+    ) -> CompactValue {
+        match self {
+            CompactValue::KeyType(_) => self.clone(),
+            CompactValue::Array(v) => CompactValue::Array(
+                v.iter()
+                    .map(|x| x.replace_shared(hoisted, ids, defs))
+                    .collect(),
+            ),
+            CompactValue::Object(o) => CompactValue::Object(
+                o.iter()
+                    .map(|(k, v)| (k.clone(), v.replace_shared(hoisted, ids, defs)))
+                    .collect(),
+            ),
+        }
+    }
+
+    // Structurally identical subtrees found more than once are written once, similar to JSON Schema:
+    // {"$defs": {"1": subtree, ...}, "data": ...}, each occurrence replaced by {"$ref": "#/$defs/1"}.
+    // Without any repeated subtree, the tree is kept as is
+    pub fn hoist_shared_subtrees(self) -> Self {
+        let mut counts = HashMap::new();
+        self.count_subtrees(&mut counts);
+        let mut hoisted = HashMap::new();
+        self.count_hoisted(&counts, &mut hoisted);
+        let mut defs = Vec::new();
+        let data = self.replace_shared(&hoisted, &mut HashMap::new(), &mut defs);
+        if defs.is_empty() {
+            return data;
+        }
+        let key = |s: &str| CompactKey::from(StringType::String(s.to_string()));
+        let defs = defs
+            .into_iter()
+            .flatten()
+            .enumerate()
+            .map(|(id, def)| (key(&(id + 1).to_string()), def))
+            .collect();
+        CompactValue::Object(BTreeSet::from([
+            (key("$defs"), CompactValue::Object(defs)),
+            (key("data"), data),
+        ]))
+    }
+
+    pub fn choose_lang(self, fmt: &LabelFormat) -> Value {
+        match self {
+            CompactValue::KeyType(k) => k.choose_lang(fmt).into(),
             CompactValue::Array(v) => {
-                Value::Array(v.into_iter().map(|x| x.choose_lang(langs)).collect())
+                Value::Array(v.into_iter().map(|x| x.choose_lang(fmt)).collect())
             }
-            CompactValue::Object(o) => Value::Object(
+            CompactValue::Object(o) => fmt.collect_object(
                 o.into_iter()
-                    .map(|(k, v)| (k.choose_lang(langs), v.choose_lang(langs)))
-                    .collect(),
+                    .map(|(k, v)| (k.choose_lang(fmt), v.choose_lang(fmt))),
             ),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn text(s: &str) -> CompactValue {
+        CompactValue::KeyType(StringType::String(s.to_string()).into())
+    }
+
+    fn object<const N: usize>(pairs: [(&str, CompactValue); N]) -> CompactValue {
+        CompactValue::Object(
+            pairs
+                .into_iter()
+                .map(|(k, v)| (StringType::String(k.to_string()).into(), v))
+                .collect(),
+        )
+    }
+
+    fn to_json(val: CompactValue) -> Value {
+        val.choose_lang(&LabelFormat::from_langs(vec!["Z1002".to_string()]))
+    }
+
+    #[test]
+    fn collapse_singletons() {
+        let val = object([
+            ("one", CompactValue::Array(vec![text("a")])),
+            ("two", CompactValue::Array(vec![text("a"), text("b")])),
+        ]);
+        assert_eq!(
+            to_json(val.clone()),
+            json!({"one": ["a"], "two": ["a", "b"]})
+        );
+        assert_eq!(
+            to_json(val.collapse_singletons()),
+            json!({"one": "a", "two": ["a", "b"]})
+        );
+    }
+
+    #[test]
+    fn hoist_shared_subtrees() {
+        let shared = || object([("x", text("1")), ("y", text("2")), ("z", text("3"))]);
+        let val = object([
+            ("a", shared()),
+            ("b", CompactValue::Array(vec![shared(), text("c")])),
+        ]);
+        assert_eq!(
+            to_json(val.hoist_shared_subtrees()),
+            json!({
+                "$defs": {"1": {"x": "1", "y": "2", "z": "3"}},
+                "data": {"a": {"$ref": "#/$defs/1"}, "b": [{"$ref": "#/$defs/1"}, "c"]}
+            })
+        );
+    }
+
+    #[test]
+    fn hoist_nothing_shared() {
+        let val = object([
+            (
+                "a",
+                object([("x", text("1")), ("y", text("2")), ("z", text("3"))]),
+            ),
+            (
+                "b",
+                object([("x", text("1")), ("y", text("2")), ("z", text("4"))]),
+            ),
+        ]);
+        assert_eq!(to_json(val.clone().hoist_shared_subtrees()), to_json(val));
+    }
+}
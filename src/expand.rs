@@ -0,0 +1,186 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+// Expanding the output of /compactify back into a ZObject, in normal form:
+// - labels are dropped, "Z801: Echo" is read back as "Z801"
+// - the types merged into keys, "key [type, ...]", are split back into Z1K1
+// - ZIDs and keys are expanded into references (Z9), other strings into strings (Z6),
+//   and strings with a language, "text [Z1002]", into monolingual texts (Z11)
+// Functions (Z8), empty lists and pairs are kept in their compact form,
+// and what the lossy options (e.g. collapse_singletons) dropped can't be recovered.
+
+static LABELLED: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\[?(Z\d+(?:K\d+)?|K\d+): .*$").unwrap());
+static ZID: Lazy<Regex> = Lazy::new(|| Regex::new(r"^Z\d+(K\d+)?$").unwrap());
+static KEY: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(Z\d+)?K\d+$").unwrap());
+
+// the raw ZID or key of a label, the string itself if it isn't labelled
+fn strip_label(s: &str) -> String {
+    match LABELLED.captures(s) {
+        Some(c) => c[1].to_string(),
+        None => s.to_string(),
+    }
+}
+
+fn is_key(s: &str) -> bool {
+    KEY.is_match(&strip_label(s))
+}
+
+// "label [type, type]" split into the label and the types,
+// the label is empty for the keys of typed objects without a key, "[type]"
+fn split_types(s: &str) -> (&str, Vec<String>) {
+    let (label, types) = match s.strip_suffix(']') {
+        Some(rest) => match rest.rfind(" [") {
+            Some(i) => (&s[..i], &rest[i + 2..]),
+            None => match rest.strip_prefix('[') {
+                Some(types) if !types.contains('[') => ("", types),
+                _ => return (s, Vec::new()),
+            },
+        },
+        None => return (s, Vec::new()),
+    };
+    // labels may contain ", " too, only the ones followed by a ZID or key separate the types
+    let mut split: Vec<String> = Vec::new();
+    for part in types.split(", ") {
+        match split.last_mut() {
+            Some(last)
+                if !(part.starts_with('Z') || part.starts_with('K') || part.starts_with('[')) =>
+            {
+                last.push_str(", ");
+                last.push_str(part);
+            }
+            _ => split.push(part.to_string()),
+        }
+    }
+    (label, split)
+}
+
+fn reference(z: &str) -> Value {
+    json!({"Z1K1": "Z9", "Z9K1": z})
+}
+
+fn expand_string(s: &str) -> Value {
+    let (text, types) = split_types(s);
+    match types.as_slice() {
+        [lang] if !text.is_empty() && !is_key(lang) => json!({
+            "Z1K1": reference("Z11"),
+            "Z11K1": reference(&strip_label(lang)),
+            "Z11K2": {"Z1K1": "Z6", "Z6K1": text},
+        }),
+        _ => {
+            let raw = strip_label(s);
+            if ZID.is_match(&raw) {
+                reference(&raw)
+            } else {
+                json!({"Z1K1": "Z6", "Z6K1": raw})
+            }
+        }
+    }
+}
+
+// the value of type typ, restoring its Z1K1, and the Z1K1 of the items of arrays
+fn apply_type(typ: &str, val: Value) -> Value {
+    match val {
+        Value::Object(mut o) if !o.contains_key("Z1K1") => {
+            let z1k1 = match o.remove("!Z1K1") {
+                // the arguments of the type
+                Some(Value::Object(args)) => {
+                    let mut z1k1 = Map::from_iter([("Z1K1".to_string(), reference(typ))]);
+                    z1k1.extend(args);
+                    Value::Object(z1k1)
+                }
+                _ => reference(typ),
+            };
+            let mut typed = Map::from_iter([("Z1K1".to_string(), z1k1)]);
+            typed.extend(o);
+            Value::Object(typed)
+        }
+        // the items had the type of the array, kept as the first element
+        Value::Array(v) => Value::Array(
+            std::iter::once(reference(typ))
+                .chain(v.into_iter().map(|x| match x {
+                    Value::Object(mut o) if !o.contains_key("Z1K1") => {
+                        o.remove("@dropped_type");
+                        apply_type(typ, Value::Object(o))
+                    }
+                    x => x,
+                }))
+                .collect(),
+        ),
+        val => val,
+    }
+}
+
+// the value of a key with types [type or key, ...]: each type applies to the value after it,
+// and each key is an object of that only key, lifted into the key by compress_simple_classes
+fn expand_typed(types: &[String], val: &Value) -> Value {
+    match types.split_first() {
+        None => expand(val),
+        Some((key, rest)) if is_key(key) => Value::Object(Map::from_iter([(
+            strip_label(key),
+            expand_typed(rest, val),
+        )])),
+        Some((typ, rest)) => apply_type(&strip_label(typ), expand_typed(rest, val)),
+    }
+}
+
+fn expand_object(o: &Map<String, Value>) -> Value {
+    let mut expanded = Map::new();
+    for (k, v) in o {
+        if k == "!Z1K1" {
+            expanded.insert(k.clone(), expand(v));
+            continue;
+        }
+        match split_types(k) {
+            // a typed object without key, its entries are those of the object
+            ("", types) => match expand_typed(&types, v) {
+                Value::Object(inner) => expanded.extend(inner),
+                inner => {
+                    expanded.insert(k.clone(), inner);
+                }
+            },
+            (key, types) => {
+                expanded.insert(strip_label(key), expand_typed(&types, v));
+            }
+        }
+    }
+    Value::Object(expanded)
+}
+
+pub fn expand(val: &Value) -> Value {
+    match val {
+        Value::String(s) => expand_string(s),
+        Value::Array(a) => Value::Array(a.iter().map(expand).collect()),
+        Value::Object(o) => expand_object(o),
+        scalar => scalar.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::labelize::tests::source;
+    use crate::{compactify, labelize, LabelFormat};
+
+    #[actix_web::test]
+    async fn compactify_round_trip() {
+        let fmt = LabelFormat::from_langs(vec!["Z1002".to_string()]);
+        // a call of Z10001 (Echo), in normal form: references and strings in their Z9/Z6 wrappers
+        let v = json!({
+            "Z1K1": {"Z1K1": "Z9", "Z9K1": "Z7"},
+            "Z7K1": {"Z1K1": "Z9", "Z9K1": "Z10001"},
+            "Z10001K1": {"Z1K1": "Z6", "Z6K1": "hello"},
+            "Z10001K2": {"Z1K1": "Z9", "Z9K1": "Z10001"},
+            "Z10001K3": {
+                "Z1K1": {"Z1K1": "Z9", "Z9K1": "Z11"},
+                "Z11K1": {"Z1K1": "Z9", "Z9K1": "Z1002"},
+                "Z11K2": {"Z1K1": "Z6", "Z6K1": "hello"}
+            }
+        });
+        let compact = compactify(labelize(v.clone(), &fmt, source()).await, &fmt, false);
+        // the wrappers are gone from the compact form, the labels are dropped again by expand
+        let compact = compact.choose_lang(&fmt);
+        assert_eq!(compact["[Z7: <unresolved>]"]["Z10001K2"], "Z10001: Echo");
+        assert_eq!(expand(&compact), v);
+    }
+}
@@ -0,0 +1,11 @@
+use serde_json::Value;
+
+// Transformation of the final json of a response, given the languages of the request,
+// e.g. to add metadata or rename keys, applied right before the response is returned
+pub type PostProcess = Box<dyn Fn(Value, &[String]) -> Value + Send + Sync>;
+
+// The hook registered at compile time, none by default. To register one, return it here, e.g.
+// Some(Box::new(|mut val, _langs| { val["tenant"] = "example".into(); val }))
+pub fn post_process() -> Option<PostProcess> {
+    None
+}
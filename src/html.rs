@@ -0,0 +1,121 @@
+use serde_json::Value;
+
+// the <style> of the index page, so the rendered results look the same
+fn index_style() -> &'static str {
+    let index = include_str!("../static/index.html");
+    match (index.find("<style>"), index.find("</style>")) {
+        (Some(start), Some(end)) => &index[start..end + "</style>".len()],
+        _ => "",
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// objects are rendered as definition lists, arrays of objects as tables (one column per key),
+// other arrays as ordered lists
+fn render_value(val: &Value, out: &mut String) {
+    match val {
+        Value::Object(o) => {
+            out.push_str("<dl>");
+            for (k, v) in o {
+                out.push_str("<dt>");
+                out.push_str(&escape(k));
+                out.push_str("</dt><dd>");
+                render_value(v, out);
+                out.push_str("</dd>");
+            }
+            out.push_str("</dl>");
+        }
+        Value::Array(v) if !v.is_empty() && v.iter().all(Value::is_object) => render_table(v, out),
+        Value::Array(v) => {
+            out.push_str("<ol>");
+            for x in v {
+                out.push_str("<li>");
+                render_value(x, out);
+                out.push_str("</li>");
+            }
+            out.push_str("</ol>");
+        }
+        Value::String(s) => out.push_str(&escape(s)),
+        other => out.push_str(&escape(&other.to_string())),
+    }
+}
+
+// the columns are the keys of all the rows, in the order they first appear
+fn render_table(rows: &[Value], out: &mut String) {
+    let mut columns: Vec<&String> = vec![];
+    for k in rows
+        .iter()
+        .filter_map(Value::as_object)
+        .flat_map(|o| o.keys())
+    {
+        if !columns.contains(&k) {
+            columns.push(k);
+        }
+    }
+    out.push_str("<table><thead><tr>");
+    for k in &columns {
+        out.push_str("<th>");
+        out.push_str(&escape(k));
+        out.push_str("</th>");
+    }
+    out.push_str("</tr></thead><tbody>");
+    for row in rows.iter().filter_map(Value::as_object) {
+        out.push_str("<tr>");
+        for k in &columns {
+            out.push_str("<td>");
+            if let Some(v) = row.get(k.as_str()) {
+                render_value(v, out);
+            }
+            out.push_str("</td>");
+        }
+        out.push_str("</tr>");
+    }
+    out.push_str("</tbody></table>");
+}
+
+// a standalone html page showing the (compactified) json
+pub fn render_page(val: &Value) -> String {
+    let mut body = String::new();
+    render_value(val, &mut body);
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\" />\n<title>Wikifunction Labelize Tool</title>\n{}\n<style>dd {{ margin-left: 1.5em; }} table {{ border-collapse: collapse; }} th, td {{ border: 1px solid; padding: 0.2em 0.5em; text-align: left; vertical-align: top; }}</style>\n</head>\n<body>\n{}\n</body>\n</html>\n",
+        index_style(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn array_of_objects_as_table() {
+        let mut out = String::new();
+        render_value(&json!([{"a": 1, "b": "x"}, {"c": "<y>", "a": 2}]), &mut out);
+        assert_eq!(
+            out,
+            "<table><thead><tr><th>a</th><th>b</th><th>c</th></tr></thead><tbody>\
+             <tr><td>1</td><td>x</td><td></td></tr>\
+             <tr><td>2</td><td></td><td>&lt;y&gt;</td></tr>\
+             </tbody></table>"
+        );
+    }
+
+    #[test]
+    fn other_arrays_as_lists() {
+        let mut out = String::new();
+        render_value(&json!({"k": ["a", {"b": 1}]}), &mut out);
+        assert_eq!(
+            out,
+            "<dl><dt>k</dt><dd><ol><li>a</li><li><dl><dt>b</dt><dd>1</dd></dl></li></ol></dd></dl>"
+        );
+    }
+}
@@ -1,7 +1,8 @@
+use serde::ser::{Serialize, SerializeTupleVariant, Serializer};
 use serde_json::{json, Value};
 
 use crate::compact_key::SimpleType;
-use crate::simple_value::StringType;
+use crate::simple_value::{LabelFormat, StringType};
 use crate::typed_form::{Type, TypedForm};
 
 type IntermediateObjectType = std::collections::BTreeSet<(StringType, IntermediateForm)>;
@@ -23,13 +24,25 @@ impl From<Type> for IntermediateType {
     }
 }
 
+// the ZID of the type referenced indirectly by the arguments of a type, {Z1K1: {Z9K1: ZID}}
+fn referenced_type(args: &IntermediateObjectType) -> Option<StringType> {
+    match &args.iter().find(|(k, _v)| k.is_labelled("Z1K1"))?.1 {
+        IntermediateForm::Object(obj) => match &obj.iter().find(|(k, _v)| k.is_labelled("Z9K1"))?.1
+        {
+            IntermediateForm::StringType(s) => Some(s.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 impl IntermediateType {
-    pub fn choose_lang(self, langs: &Vec<String>) -> Value {
+    pub fn choose_lang(self, fmt: &LabelFormat) -> Value {
         match self {
-            Self::Simple(k) => k.choose_lang(langs).into(),
+            Self::Simple(k) => k.choose_lang(fmt).into(),
             Self::WithArgs(typ, args) => {
-                json!({"type": typ.choose_lang(langs), "args": Value::Object(
-                    args.into_iter().map(|(k,v)| (k.choose_lang(langs), v.choose_lang(langs))).collect()
+                json!({"type": typ.choose_lang(fmt), "args": fmt.collect_object(
+                    args.into_iter().map(|(k,v)| (k.choose_lang(fmt), v.choose_lang(fmt)))
                 )})
             }
         }
@@ -50,6 +63,54 @@ pub enum IntermediateForm {
     TypedObject(IntermediateType, IntermediateObjectType),
 }
 
+// A faithful serialization of the IR, for tooling doing its own rendering,
+// unlike choose_lang() the variants are kept, externally tagged like serde does by default
+impl Serialize for IntermediateType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Simple(typ) => {
+                serializer.serialize_newtype_variant("IntermediateType", 0, "Simple", typ)
+            }
+            Self::WithArgs(typ, args) => {
+                let mut v =
+                    serializer.serialize_tuple_variant("IntermediateType", 1, "WithArgs", 2)?;
+                v.serialize_field(typ)?;
+                v.serialize_field(args)?;
+                v.end()
+            }
+        }
+    }
+}
+
+impl Serialize for IntermediateForm {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        const NAME: &str = "IntermediateForm";
+        match self {
+            Self::StringType(s) => serializer.serialize_newtype_variant(NAME, 0, "StringType", s),
+            Self::LabelledNode(s, t) => {
+                let mut v = serializer.serialize_tuple_variant(NAME, 1, "LabelledNode", 2)?;
+                v.serialize_field(s)?;
+                v.serialize_field(t)?;
+                v.end()
+            }
+            Self::Array(arr) => serializer.serialize_newtype_variant(NAME, 2, "Array", arr),
+            Self::TypedArray(typ, arr) => {
+                let mut v = serializer.serialize_tuple_variant(NAME, 3, "TypedArray", 2)?;
+                v.serialize_field(typ)?;
+                v.serialize_field(arr)?;
+                v.end()
+            }
+            Self::Object(obj) => serializer.serialize_newtype_variant(NAME, 4, "Object", obj),
+            Self::TypedObject(typ, obj) => {
+                let mut v = serializer.serialize_tuple_variant(NAME, 5, "TypedObject", 2)?;
+                v.serialize_field(typ)?;
+                v.serialize_field(obj)?;
+                v.end()
+            }
+        }
+    }
+}
+
 impl From<TypedForm> for IntermediateForm {
     fn from(val: TypedForm) -> Self {
         match val {
@@ -69,9 +130,12 @@ impl From<TypedForm> for IntermediateForm {
     }
 }
 
-fn drop_array_item_types(obj: IntermediateObjectType) -> IntermediateObjectType {
+fn drop_array_item_types(
+    obj: IntermediateObjectType,
+    mark_dropped: bool,
+) -> IntermediateObjectType {
     obj.into_iter()
-        .map(|(k, v)| (k, v.drop_array_item_types()))
+        .map(|(k, v)| (k, v.drop_array_item_types(mark_dropped)))
         .collect()
 }
 
@@ -93,12 +157,43 @@ fn compress_monolingual(obj: IntermediateObjectType) -> IntermediateObjectType {
         .collect()
 }
 
+fn compress_function(obj: IntermediateObjectType) -> IntermediateObjectType {
+    obj.into_iter()
+        .map(|(k, v)| (k, v.compress_function()))
+        .collect()
+}
+
+fn compress_list(obj: IntermediateObjectType) -> IntermediateObjectType {
+    obj.into_iter()
+        .map(|(k, v)| (k, v.compress_list()))
+        .collect()
+}
+
+// An argument declaration (Z17) as a pair of the argument key (Z17K2) and its type (Z17K1).
+// The label of the argument (Z17K3) is dropped, the argument key is labelled with the same label.
+fn argument_declaration(arg: &IntermediateForm) -> Option<(StringType, IntermediateForm)> {
+    match arg {
+        IntermediateForm::TypedObject(IntermediateType::Simple(typ), obj)
+            if typ.is_labelled("Z17") =>
+        {
+            match (
+                &obj.iter().find(|(k, _v)| k.is_labelled("Z17K2"))?.1,
+                &obj.iter().find(|(k, _v)| k.is_labelled("Z17K1"))?.1,
+            ) {
+                (IntermediateForm::StringType(key), typ) => Some((key.clone(), typ.clone())),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
 impl IntermediateType {
-    fn drop_array_item_types(self) -> Self {
+    fn drop_array_item_types(self, mark_dropped: bool) -> Self {
         match self {
             IntermediateType::Simple(_) => self,
             IntermediateType::WithArgs(typ, args) => {
-                IntermediateType::WithArgs(typ, drop_array_item_types(args))
+                IntermediateType::WithArgs(typ, drop_array_item_types(args, mark_dropped))
             }
         }
     }
@@ -106,28 +201,23 @@ impl IntermediateType {
     fn compress_reference(self) -> Self {
         match self {
             IntermediateType::Simple(_) => self,
-            // TODO: this seems bad, too many assumptions, need refactor
             IntermediateType::WithArgs(typ, args) => {
                 if typ.is_labelled("Z9") {
                     match args.iter().find(|(k, _v)| k.is_labelled("Z9K1")) {
-                        Some((_z9k1, v)) => match v {
-                            IntermediateForm::StringType(s) => IntermediateType::Simple(s.clone()),
-                            _ => todo!(),
-                        },
-                        None => match &args.iter().find(|(k, _v)| k.is_labelled("Z1K1")).unwrap().1
-                        {
-                            IntermediateForm::Object(obj) => {
-                                match &obj.iter().find(|(k, _v)| k.is_labelled("Z9K1")).unwrap().1 {
-                                    IntermediateForm::StringType(s) => IntermediateType::WithArgs(
-                                        s.clone(),
-                                        args.into_iter()
-                                            .filter(|(k, _v)| !k.is_labelled("Z1K1"))
-                                            .collect(),
-                                    ),
-                                    _ => todo!(),
-                                }
-                            }
-                            _ => todo!(),
+                        Some((_z9k1, IntermediateForm::StringType(s))) => {
+                            IntermediateType::Simple(s.clone().resolve_redirect())
+                        }
+                        // not a literal ZID, the reference is left uncompressed
+                        Some(_) => IntermediateType::WithArgs(typ, compress_reference(args)),
+                        None => match referenced_type(&args) {
+                            Some(s) => IntermediateType::WithArgs(
+                                s,
+                                args.into_iter()
+                                    .filter(|(k, _v)| !k.is_labelled("Z1K1"))
+                                    .collect(),
+                            ),
+                            // not the expected shape, the type is left as is
+                            None => IntermediateType::WithArgs(typ, args),
                         },
                     }
                 } else {
@@ -142,14 +232,15 @@ impl IntermediateType {
             IntermediateType::Simple(_) => self,
             IntermediateType::WithArgs(typ, args) => {
                 if typ.is_labelled("Z6") {
-                    IntermediateType::Simple(
-                        match &args.iter().find(|(k, _v)| k.is_labelled("Z6K1")).unwrap().1 {
-                            IntermediateForm::StringType(s) => s.clone(),
-                            _ => todo!(),
-                        },
-                    )
+                    match args.iter().find(|(k, _v)| k.is_labelled("Z6K1")) {
+                        Some((_z6k1, IntermediateForm::StringType(s))) => {
+                            IntermediateType::Simple(s.clone())
+                        }
+                        // not a literal string, the type is kept as is
+                        _ => IntermediateType::WithArgs(typ, compress_string(args)),
+                    }
                 } else {
-                    IntermediateType::WithArgs(typ, compress_reference(args))
+                    IntermediateType::WithArgs(typ, compress_string(args))
                 }
             }
         }
@@ -163,29 +254,55 @@ impl IntermediateType {
             }
         }
     }
+
+    fn compress_function(self) -> Self {
+        match self {
+            IntermediateType::Simple(_) => self,
+            IntermediateType::WithArgs(typ, args) => {
+                IntermediateType::WithArgs(typ, compress_function(args))
+            }
+        }
+    }
 }
 
 impl IntermediateForm {
-    pub fn drop_array_item_types(self) -> Self {
+    // with mark_dropped, the items whose type was dropped get a key "@dropped_type": "Z1K1",
+    // recording where the type has to be restored from the type of the array
+    pub fn drop_array_item_types(self, mark_dropped: bool) -> Self {
         match self {
             IntermediateForm::TypedArray(typ, v) => IntermediateForm::TypedArray(
                 typ,
                 v.into_iter()
                     .map(|x| match x {
-                        IntermediateForm::TypedObject(_typ, obj) => {
-                            IntermediateForm::Object(obj).drop_array_item_types()
-                        }
-                        _ => x.drop_array_item_types(),
+                        IntermediateForm::TypedObject(_typ, obj) => IntermediateForm::Object(
+                            obj.into_iter()
+                                .chain(mark_dropped.then(|| {
+                                    (
+                                        StringType::String("@dropped_type".to_string()),
+                                        IntermediateForm::StringType(StringType::String(
+                                            "Z1K1".to_string(),
+                                        )),
+                                    )
+                                }))
+                                .collect(),
+                        )
+                        .drop_array_item_types(mark_dropped),
+                        _ => x.drop_array_item_types(mark_dropped),
                     })
                     .collect(),
             ),
             IntermediateForm::Array(arr) => IntermediateForm::Array(
-                arr.into_iter().map(|x| x.drop_array_item_types()).collect(),
+                arr.into_iter()
+                    .map(|x| x.drop_array_item_types(mark_dropped))
+                    .collect(),
             ),
-            IntermediateForm::Object(obj) => IntermediateForm::Object(drop_array_item_types(obj)),
-            IntermediateForm::TypedObject(t, o) => {
-                IntermediateForm::TypedObject(t.drop_array_item_types(), drop_array_item_types(o))
+            IntermediateForm::Object(obj) => {
+                IntermediateForm::Object(drop_array_item_types(obj, mark_dropped))
             }
+            IntermediateForm::TypedObject(t, o) => IntermediateForm::TypedObject(
+                t.drop_array_item_types(mark_dropped),
+                drop_array_item_types(o, mark_dropped),
+            ),
             IntermediateForm::StringType(_) => self,
             IntermediateForm::LabelledNode(_, _) => self,
         }
@@ -194,23 +311,31 @@ impl IntermediateForm {
     pub fn compress_reference(self) -> Self {
         match self {
             IntermediateForm::TypedObject(IntermediateType::Simple(typ), obj) => {
-                if typ.is_labelled("Z9") {
-                    IntermediateForm::StringType(
-                        match &obj.iter().find(|(k, _v)| k.is_labelled("Z9K1")).unwrap().1 {
-                            IntermediateForm::StringType(s) => s.clone(),
-                            _ => todo!("non-string value for Z9K1"),
-                        },
-                    )
-                } else {
-                    IntermediateForm::TypedObject(
+                // the ZID of a reference (Z9) is the value of Z9K1
+                let zid = match typ.is_labelled("Z9") {
+                    true => obj.iter().find_map(|(k, v)| match v {
+                        IntermediateForm::StringType(s) if k.is_labelled("Z9K1") => Some(s.clone()),
+                        _ => None,
+                    }),
+                    false => None,
+                };
+                match zid {
+                    Some(s) => IntermediateForm::StringType(s.resolve_redirect()),
+                    // Z9K1 missing or not a literal ZID, the reference is left uncompressed
+                    None => IntermediateForm::TypedObject(
                         IntermediateType::Simple(typ),
                         compress_reference(obj),
-                    )
+                    ),
                 }
             }
-            IntermediateForm::TypedObject(typ, obj) => {
-                IntermediateForm::TypedObject(typ.compress_reference(), compress_reference(obj))
-            }
+            IntermediateForm::TypedObject(typ, obj) => match typ.compress_reference() {
+                // the type itself was an expanded reference (normal form), e.g. to Z9,
+                // the object is compressed again, now with the simple type
+                typ @ IntermediateType::Simple(_) => {
+                    IntermediateForm::TypedObject(typ, obj).compress_reference()
+                }
+                typ => IntermediateForm::TypedObject(typ, compress_reference(obj)),
+            },
             IntermediateForm::StringType(_) => self,
             IntermediateForm::LabelledNode(_, _) => self,
             IntermediateForm::Array(v) => {
@@ -227,27 +352,23 @@ impl IntermediateForm {
     pub fn compress_string(self) -> Self {
         match self {
             IntermediateForm::TypedObject(IntermediateType::Simple(typ), obj) => {
-                // if the object has type String (Z6)
-                if typ.is_labelled("Z6") {
-                    IntermediateForm::StringType(
-                        // there should be key Z6K1 containing the actual string
-                        match obj
-                            .into_iter()
-                            .find(|(k, _v)| k.is_labelled("Z6K1"))
-                            .unwrap()
-                            .1
-                        {
-                            // if the string is labelled, it should not be, we turn it back to a normal string
-                            IntermediateForm::StringType(s) => StringType::String(s.into_raw()),
-                            // ...wait can it be a function call?
-                            _ => todo!("non-string value for Z6K1"),
-                        },
-                    )
-                } else {
-                    IntermediateForm::TypedObject(
+                // if the object has type String (Z6),
+                // there should be key Z6K1 containing the actual string
+                let string = match typ.is_labelled("Z6") {
+                    true => obj.iter().find_map(|(k, v)| match v {
+                        IntermediateForm::StringType(s) if k.is_labelled("Z6K1") => Some(s.clone()),
+                        _ => None,
+                    }),
+                    false => None,
+                };
+                match string {
+                    // if the string is labelled, it should not be, we turn it back to a normal string
+                    Some(s) => IntermediateForm::StringType(StringType::String(s.into_raw())),
+                    // Z6K1 can also be e.g. a function call, the object is then left uncompressed
+                    None => IntermediateForm::TypedObject(
                         IntermediateType::Simple(typ),
                         compress_string(obj),
-                    )
+                    ),
                 }
             }
             IntermediateForm::TypedObject(typ, obj) => {
@@ -273,22 +394,17 @@ impl IntermediateForm {
         // type: the language, value of Z11K1
         match self {
             IntermediateForm::TypedObject(IntermediateType::Simple(typ), obj) => {
-                if typ.is_labelled("Z11") {
-                    IntermediateForm::LabelledNode(
-                        match &obj.iter().find(|(k, _v)| k.is_labelled("Z11K2")).unwrap().1 {
-                            IntermediateForm::StringType(s) => s.clone(),
-                            _ => todo!(),
-                        },
-                        match obj
-                            .into_iter()
-                            .find(|(k, _v)| k.is_labelled("Z11K1"))
-                            .unwrap()
-                            .1
-                        {
-                            IntermediateForm::StringType(s) => SimpleType(s),
-                            _ => todo!(),
-                        },
-                    )
+                let text = |key: &str| match typ.is_labelled("Z11") {
+                    true => obj.iter().find_map(|(k, v)| match v {
+                        IntermediateForm::StringType(s) if k.is_labelled(key) => Some(s.clone()),
+                        _ => None,
+                    }),
+                    false => None,
+                };
+                // Z11K1 and Z11K2 can also be e.g. references or strings not yet compressed,
+                // the object is then left uncompressed
+                if let (Some(text), Some(lang)) = (text("Z11K2"), text("Z11K1")) {
+                    IntermediateForm::LabelledNode(text, SimpleType(lang))
                 } else {
                     IntermediateForm::TypedObject(
                         IntermediateType::Simple(typ),
@@ -313,34 +429,277 @@ impl IntermediateForm {
     }
 }
 
+impl IntermediateForm {
+    pub fn compress_function(self) -> Self {
+        // we present objects of type Z8 (Function) by their signature:
+        // - the arguments (Z8K1) become an object of {argument key: argument type}
+        // - the testers (Z8K3) and implementations (Z8K4) are folded into plain lists of references
+        match self {
+            IntermediateForm::TypedObject(IntermediateType::Simple(typ), obj) => {
+                if typ.is_labelled("Z8") {
+                    IntermediateForm::TypedObject(
+                        IntermediateType::Simple(typ),
+                        obj.into_iter()
+                            .map(|(k, v)| match v {
+                                IntermediateForm::TypedArray(_, args) if k.is_labelled("Z8K1") => {
+                                    let declarations: Option<IntermediateObjectType> = args
+                                        .iter()
+                                        .map(|arg| {
+                                            argument_declaration(arg)
+                                                .map(|(key, typ)| (key, typ.compress_function()))
+                                        })
+                                        .collect();
+                                    match declarations {
+                                        Some(declarations) => {
+                                            (k, IntermediateForm::Object(declarations))
+                                        }
+                                        None => (
+                                            k,
+                                            IntermediateForm::Array(
+                                                args.into_iter()
+                                                    .map(|x| x.compress_function())
+                                                    .collect(),
+                                            ),
+                                        ),
+                                    }
+                                }
+                                IntermediateForm::TypedArray(_, v)
+                                    if k.is_labelled("Z8K3") || k.is_labelled("Z8K4") =>
+                                {
+                                    (
+                                        k,
+                                        IntermediateForm::Array(
+                                            v.into_iter().map(|x| x.compress_function()).collect(),
+                                        ),
+                                    )
+                                }
+                                _ => (k, v.compress_function()),
+                            })
+                            .collect(),
+                    )
+                } else {
+                    IntermediateForm::TypedObject(
+                        IntermediateType::Simple(typ),
+                        compress_function(obj),
+                    )
+                }
+            }
+            IntermediateForm::TypedObject(typ, obj) => {
+                IntermediateForm::TypedObject(typ.compress_function(), compress_function(obj))
+            }
+            IntermediateForm::StringType(_) => self,
+            IntermediateForm::LabelledNode(_, _) => self,
+            IntermediateForm::Array(v) => {
+                IntermediateForm::Array(v.into_iter().map(|x| x.compress_function()).collect())
+            }
+            IntermediateForm::TypedArray(typ, v) => IntermediateForm::TypedArray(
+                typ.compress_function(),
+                v.into_iter().map(|x| x.compress_function()).collect(),
+            ),
+            IntermediateForm::Object(obj) => IntermediateForm::Object(compress_function(obj)),
+        }
+    }
+}
+
+impl IntermediateType {
+    // a Typed Pair (Z882), as the type itself, or as the function call building it (Z7 of Z882)
+    fn is_pair(&self) -> bool {
+        match self {
+            IntermediateType::Simple(typ) => typ.is_labelled("Z882"),
+            IntermediateType::WithArgs(typ, args) => {
+                typ.is_labelled("Z882")
+                    || (typ.is_labelled("Z7") && args.iter().any(|(k, v)| {
+                        k.is_labelled("Z7K1")
+                            && matches!(v, IntermediateForm::StringType(f) if f.is_labelled("Z882"))
+                    }))
+            }
+        }
+    }
+
+    fn compress_list(self) -> Self {
+        match self {
+            IntermediateType::Simple(_) => self,
+            IntermediateType::WithArgs(typ, args) => {
+                IntermediateType::WithArgs(typ, compress_list(args))
+            }
+        }
+    }
+}
+
+impl IntermediateForm {
+    // we present the rest of the list and map family:
+    // - the empty list (Z13) as an empty array
+    // - a typed pair (Z882) as an array of its two elements (K1, K2), a tuple
+    pub fn compress_list(self) -> Self {
+        match self {
+            IntermediateForm::TypedObject(IntermediateType::Simple(typ), obj)
+                if typ.is_labelled("Z13") && obj.is_empty() =>
+            {
+                IntermediateForm::Array(Vec::new())
+            }
+            IntermediateForm::TypedObject(typ, obj) if typ.is_pair() => {
+                let element = |key: &str| {
+                    obj.iter()
+                        .find(|(k, _v)| k.is_labelled(key))
+                        .map(|(_k, v)| v.clone())
+                };
+                match (element("K1"), element("K2")) {
+                    (Some(first), Some(second)) if obj.len() == 2 => {
+                        IntermediateForm::Array(vec![first.compress_list(), second.compress_list()])
+                    }
+                    _ => IntermediateForm::TypedObject(typ.compress_list(), compress_list(obj)),
+                }
+            }
+            IntermediateForm::TypedObject(typ, obj) => {
+                IntermediateForm::TypedObject(typ.compress_list(), compress_list(obj))
+            }
+            IntermediateForm::StringType(_) => self,
+            IntermediateForm::LabelledNode(_, _) => self,
+            IntermediateForm::Array(v) => {
+                IntermediateForm::Array(v.into_iter().map(|x| x.compress_list()).collect())
+            }
+            IntermediateForm::TypedArray(typ, v) => IntermediateForm::TypedArray(
+                typ.compress_list(),
+                v.into_iter().map(|x| x.compress_list()).collect(),
+            ),
+            IntermediateForm::Object(obj) => IntermediateForm::Object(compress_list(obj)),
+        }
+    }
+}
+
 impl IntermediateForm {
     // this is mostly for debugging purpose, should not be returned via api
-    pub fn choose_lang(self, langs: &Vec<String>) -> Value {
+    pub fn choose_lang(self, fmt: &LabelFormat) -> Value {
         match self {
-            IntermediateForm::StringType(s) => s.choose_lang(langs).into(),
+            IntermediateForm::StringType(s) => s.choose_lang(fmt).into(),
             IntermediateForm::LabelledNode(s, t) => {
-                format!("{} [{}]", s.choose_lang(langs), t.0.choose_lang(langs),).into()
+                if SimpleType::is_omitted_lang(std::slice::from_ref(&t), fmt) {
+                    s.choose_lang(fmt).into()
+                } else {
+                    format!("{} [{}]", s.choose_lang(fmt), t.0.choose_lang(fmt),).into()
+                }
             }
             IntermediateForm::Array(v) => {
-                Value::Array((v.into_iter().map(|x| x.choose_lang(langs))).collect())
+                Value::Array((v.into_iter().map(|x| x.choose_lang(fmt))).collect())
             }
             IntermediateForm::TypedArray(typ, v) => Value::Array(
-                std::iter::once(typ.choose_lang(langs))
-                    .chain(v.into_iter().map(|x| x.choose_lang(langs)))
+                std::iter::once(typ.choose_lang(fmt))
+                    .chain(v.into_iter().map(|x| x.choose_lang(fmt)))
                     .collect(),
             ),
-            IntermediateForm::Object(o) => Value::Object(
+            IntermediateForm::Object(o) => fmt.collect_object(
                 o.into_iter()
-                    .map(|(k, v)| (k.choose_lang(langs), v.choose_lang(langs)))
-                    .collect(),
+                    .map(|(k, v)| (k.choose_lang(fmt), v.choose_lang(fmt))),
             ),
             IntermediateForm::TypedObject(typ, o) => {
-                json!({"debug type":typ.choose_lang(langs), "debug obj": Value::Object(
+                json!({"debug type":typ.choose_lang(fmt), "debug obj": fmt.collect_object(
                     o.into_iter()
-                        .map(|(k, v)| (k.choose_lang(langs), v.choose_lang(langs)))
-                        .collect(),
+                        .map(|(k, v)| (k.choose_lang(fmt), v.choose_lang(fmt))),
                 )})
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+
+    use crate::{compactify, labelize, LabelFormat, Wikifunctions};
+
+    // the compactified json of a ZObject, with its ZIDs left unlabelled, so nothing is fetched
+    async fn compact(v: Value, passes: Option<&[&str]>) -> Value {
+        let fmt = LabelFormat {
+            no_labels: true,
+            passes: passes.map(|p| p.iter().map(|s| s.to_string()).collect()),
+            ..Default::default()
+        };
+        let val = labelize(v, &fmt, Arc::new(Wikifunctions)).await;
+        compactify(val, &fmt, false).choose_lang(&fmt)
+    }
+
+    #[actix_web::test]
+    async fn monolingual_bare_and_expanded() {
+        let bare = json!({"Z1K1": "Z11", "Z11K1": "Z1002", "Z11K2": "hello"});
+        let expanded = json!({
+            "Z1K1": {"Z1K1": "Z9", "Z9K1": "Z11"},
+            "Z11K1": {"Z1K1": "Z9", "Z9K1": "Z1002"},
+            "Z11K2": {"Z1K1": "Z6", "Z6K1": "hello"}
+        });
+        let mixed = json!({
+            "Z1K1": "Z11",
+            "Z11K1": {"Z1K1": "Z9", "Z9K1": "Z1002"},
+            "Z11K2": "hello"
+        });
+        assert_eq!(compact(bare, None).await, json!("hello [Z1002]"));
+        assert_eq!(compact(expanded, None).await, json!("hello [Z1002]"));
+        assert_eq!(compact(mixed, None).await, json!("hello [Z1002]"));
+    }
+
+    #[actix_web::test]
+    async fn string_with_object_value() {
+        let call = json!({"Z1K1": "Z7", "Z7K1": "Z801", "Z801K1": "hello"});
+        let v = json!({"Z1K1": "Z6", "Z6K1": call});
+        assert_eq!(
+            compact(v, None).await,
+            json!({"[Z6, Z6K1, Z7]": {"Z7K1": "Z801", "Z801K1": "hello"}})
+        );
+        // the same in the arguments of a type
+        let typ = json!({"Z1K1": "Z7", "Z7K1": "Z881", "Z881K1": {"Z1K1": "Z6", "Z6K1": call}});
+        assert_eq!(
+            compact(json!({"Z1K1": typ, "Z2K1": "x"}), None).await,
+            json!({"[Z7]": {
+                "!Z1K1": {
+                    "Z7K1": "Z881",
+                    "Z881K1 [Z6, Z6K1, Z7]": {"Z7K1": "Z801", "Z801K1": "hello"}
+                },
+                "Z2K1": "x"
+            }})
+        );
+        // only strings are compressed in the arguments of a type by the string pass
+        let typ = json!({
+            "Z1K1": "Z7",
+            "Z7K1": "Z881",
+            "Z881K1": {"Z1K1": "Z9", "Z9K1": "Z6"},
+            "Z881K2": {"Z1K1": "Z6", "Z6K1": "hello"}
+        });
+        assert_eq!(
+            compact(json!({"Z1K1": typ, "Z2K1": "x"}), Some(&["string"])).await,
+            json!({"[Z7]": {
+                "!Z1K1": {"Z7K1": "Z881", "Z881K1 [Z9]": {"Z9K1": "Z6"}, "Z881K2": "hello"},
+                "Z2K1": "x"
+            }})
+        );
+    }
+
+    #[actix_web::test]
+    async fn reference_with_object_value() {
+        let call = json!({"Z1K1": "Z7", "Z7K1": "Z801", "Z801K1": "Z11"});
+        let v = json!({"Z1K1": "Z9", "Z9K1": call});
+        assert_eq!(
+            compact(v, None).await,
+            json!({"[Z9, Z9K1, Z7]": {"Z7K1": "Z801", "Z801K1": "Z11"}})
+        );
+        // the same as the type of an object
+        let typ = json!({"Z1K1": "Z9", "Z9K1": call});
+        assert_eq!(
+            compact(json!({"Z1K1": typ, "Z2K1": "x"}), None).await,
+            json!({"[Z9]": {
+                "!Z1K1 [Z9K1, Z7]": {"Z7K1": "Z801", "Z801K1": "Z11"},
+                "Z2K1": "x"
+            }})
+        );
+    }
+
+    #[actix_web::test]
+    async fn type_reference_without_z9k1() {
+        // a reference type without the referenced ZID, left as is
+        let typ = json!({"Z1K1": "Z9", "Z9K2": "Z11"});
+        assert_eq!(
+            compact(json!({"Z1K1": typ, "Z2K1": "x"}), None).await,
+            json!({"[Z9]": {"!Z1K1 [Z9K2]": "Z11", "Z2K1": "x"}})
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use derive_more::Display;
 use regex::Regex;
@@ -6,24 +9,37 @@ use regex::Regex;
 use actix_web::HttpResponse;
 use actix_web::{error::ResponseError, http::header::ContentType};
 use async_recursion::async_recursion;
+use once_cell::sync::Lazy;
+use tokio::sync::Semaphore;
 use tracing::{debug, trace, warn};
 
-use cached::proc_macro::cached;
-use futures::future::{self, Shared};
-use futures::{Future, FutureExt};
+use futures::future::{self, Shared, WeakShared};
+use futures::{Future, FutureExt, StreamExt};
 use std::pin::Pin;
 
-use crate::simple_value::{LabelledNode, SimpleValue, StringType};
-use serde_json::Value;
+use crate::lru::LruMap;
+use crate::ordered_value::OrderedValue;
+use crate::simple_value::{
+    Incomplete, LabelFormat, LabelledNode, SimpleObject, SimpleValue, StringType,
+};
+use serde_json::{json, Value};
 
 use crate::DOMAIN;
 
+// ZIDs (Zxxx), global keys (ZxxxKyyy), and either, the ZID being the first capture group
+static ZID: Lazy<Regex> = Lazy::new(|| Regex::new(r"^Z\d+$").unwrap());
+static GLOBAL_KEY: Lazy<Regex> = Lazy::new(|| Regex::new(r"^Z\d+K\d+$").unwrap());
+static ZID_OR_KEY: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(Z\d+)(K\d+)?$").unwrap());
+
 #[derive(Debug, PartialEq, Clone, Display)]
-enum MyError {
+pub enum MyError {
     #[display(fmt = "network error: {}", _0)]
     NetworkError(String),
     #[display(fmt = "schema error: {}", _0)]
     SchemaError(String),
+    // the ZID does not exist on wikifunction
+    #[display(fmt = "not found: {}", _0)]
+    NotFound(String),
 }
 
 impl ResponseError for MyError {
@@ -38,46 +54,868 @@ impl ResponseError for MyError {
     }
 }
 
-async fn _fetch(z_number: String) -> std::result::Result<Value, MyError> {
-    debug!("fetching from wikifunction: {}", z_number);
-    match reqwest::get(format!("{}/api.php?action=query&format=json&list=wikilambdaload_zobjects&wikilambdaload_zids={}&wikilambdaload_canonical=true", DOMAIN, &z_number)).await {
-        Ok(res) => {
-            debug!("fetched from wikifunction: {}", z_number);
-            Ok(
-                serde_json::from_str::<Value>(&res.text().await.unwrap())
-                    .map_err(|_e| MyError::SchemaError("failed parsing wikifunction response".to_string()))?
-                    .get("query")
-                    .ok_or(MyError::SchemaError("no \"query\" key in wikifunction response".to_string()))?
-                    .get("wikilambdaload_zobjects")
-                    .ok_or(MyError::SchemaError("no \"wikilambdaload_zobjects\" key in wikifunction response".to_string()))?
-                    .get(&z_number)
-                    .ok_or(MyError::SchemaError(format!("no key for self ({}) in wikifunction response", z_number)))?
-                    .get("data")
-                    .ok_or(MyError::SchemaError("no \"data\" key in wikifunction response".to_string()))?
-                    .to_owned()
-            )
-        },
-        Err(e) => {
-            warn!("error fetching {}: {}", z_number, e); 
-            Err(MyError::NetworkError(e.to_string()))
+// Everything a fetch depends on, used as the key of the cache and of fetches in flight,
+// so that different forms of the same ZID are never mixed up
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FetchKey {
+    z_number: String,
+    // canonical or normal form of the ZObject
+    canonical: bool,
+}
+
+impl FetchKey {
+    fn canonical(z_number: String) -> Self {
+        Self {
+            z_number,
+            canonical: true,
+        }
+    }
+}
+
+// A page of the response of an Endpoint
+enum Page {
+    ZObject(Value),
+    // the ZObject is in a later page, fetched with these parameters added to the url
+    Continue(Vec<(String, String)>),
+}
+
+// Where ZObjects are fetched from: the url to fetch (under the domain), and how to read the response
+trait Endpoint: Send + Sync {
+    fn url(&self, key: &FetchKey) -> String;
+    fn parse(&self, z_number: &str, res: Value) -> std::result::Result<Page, MyError>;
+    // fetching several ZIDs in one request, if the endpoint supports it,
+    // returning the url, and the ZObjects found in the response
+    fn batch_url(&self, _z_numbers: &[String]) -> Option<String> {
+        None
+    }
+    fn parse_batch(&self, _res: Value) -> Vec<(String, Value)> {
+        Vec::new()
+    }
+}
+
+// a Persistent Object (Z2) without value nor labels, for pages that exist but have no content
+fn empty_persistent_object(z_number: &str) -> Value {
+    json!({
+        "Z1K1": "Z2",
+        "Z2K1": {"Z1K1": "Z6", "Z6K1": z_number},
+        "Z2K2": "",
+        "Z2K3": {"Z1K1": "Z12", "Z12K1": ["Z11"]},
+    })
+}
+
+// the MediaWiki action API, action=query&list=wikilambdaload_zobjects
+struct ActionQuery;
+
+impl Endpoint for ActionQuery {
+    fn url(&self, key: &FetchKey) -> String {
+        format!("/api.php?action=query&format=json&list=wikilambdaload_zobjects&wikilambdaload_zids={}&wikilambdaload_canonical={}", key.z_number, key.canonical)
+    }
+
+    fn parse(&self, z_number: &str, res: Value) -> std::result::Result<Page, MyError> {
+        let zobject = res
+            .get("query")
+            .and_then(|q| q.get("wikilambdaload_zobjects"))
+            .and_then(|zobjects| zobjects.get(z_number));
+        if let Some(zobject) = zobject {
+            if zobject.get("missing").is_some() {
+                return Err(MyError::NotFound(format!(
+                    "{} does not exist on wikifunction",
+                    z_number
+                )));
+            }
+            return Ok(Page::ZObject(match zobject.get("data") {
+                Some(data) => data.to_owned(),
+                // the page exists but has no content, e.g. an empty page
+                None => {
+                    debug!("no \"data\" key in wikifunction response for {}", z_number);
+                    empty_persistent_object(z_number)
+                }
+            }));
+        }
+        // the object may be in a later page of the response
+        match res.get("continue").and_then(Value::as_object) {
+            Some(cont) => Ok(Page::Continue(
+                cont.iter()
+                    .map(|(k, v)| match v {
+                        Value::String(s) => (k.clone(), s.clone()),
+                        _ => (k.clone(), v.to_string()),
+                    })
+                    .collect(),
+            )),
+            None => Err(MyError::NotFound(format!(
+                "no key for self ({}) in wikifunction response",
+                z_number
+            ))),
+        }
+    }
+
+    fn batch_url(&self, z_numbers: &[String]) -> Option<String> {
+        Some(format!("/api.php?action=query&format=json&list=wikilambdaload_zobjects&wikilambdaload_zids={}&wikilambdaload_canonical=true", z_numbers.join("|")))
+    }
+
+    // the objects missing from the response (e.g. in a later page) are left to be fetched one by one
+    fn parse_batch(&self, res: Value) -> Vec<(String, Value)> {
+        match res
+            .get("query")
+            .and_then(|q| q.get("wikilambdaload_zobjects"))
+            .and_then(Value::as_object)
+        {
+            Some(zobjects) => zobjects
+                .iter()
+                .filter_map(|(z_number, zobject)| {
+                    Some((z_number.clone(), zobject.get("data")?.clone()))
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// the REST API of WikiLambda, rest.php/wikifunctions/v0/fetch/Zxxx,
+// responding with {"Zxxx": ZObject}, where the ZObject may be serialized as a json string
+// (only the canonical form is available)
+struct Rest;
+
+impl Endpoint for Rest {
+    fn url(&self, key: &FetchKey) -> String {
+        format!("/rest.php/wikifunctions/v0/fetch/{}", key.z_number)
+    }
+
+    fn parse(&self, z_number: &str, res: Value) -> std::result::Result<Page, MyError> {
+        match res.get(z_number) {
+            Some(Value::String(s)) => serde_json::from_str(s).map(Page::ZObject).map_err(|_e| {
+                MyError::SchemaError(format!(
+                    "failed parsing ZObject {} in REST response",
+                    z_number
+                ))
+            }),
+            Some(zobject) => Ok(Page::ZObject(zobject.to_owned())),
+            None => Err(MyError::NotFound(format!(
+                "no key for self ({}) in REST response",
+                z_number
+            ))),
+        }
+    }
+}
+
+// configured with env FETCH_STRATEGY: "query" (default) or "rest"
+static ENDPOINT: Lazy<Box<dyn Endpoint>> =
+    Lazy::new(|| match std::env::var("FETCH_STRATEGY").as_deref() {
+        Ok("rest") => Box::new(Rest),
+        Ok("query") | Err(_) => Box::new(ActionQuery),
+        Ok(other) => {
+            warn!("unknown FETCH_STRATEGY {}, using query", other);
+            Box::new(ActionQuery)
+        }
+    });
+
+// maximum number of pages followed when the API paginates its response with "continue"
+const MAX_CONTINUE: usize = 10;
+
+// Timeout of each request to wikifunction (connecting and reading the response),
+// configured with env FETCH_TIMEOUT_SECS (default 10), and for known slow objects
+// FETCH_TIMEOUT_OVERRIDES, a list of ZIDs or ranges of ZIDs with their timeout, e.g. "Z4=30,Z10000-Z10999=20"
+static FETCH_TIMEOUT: Lazy<Duration> = Lazy::new(|| env_secs("FETCH_TIMEOUT_SECS", 10));
+
+// shared by all the fetches, reusing its connections
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .connect_timeout(*FETCH_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+});
+
+static FETCH_TIMEOUT_OVERRIDES: Lazy<Vec<(u64, u64, Duration)>> = Lazy::new(|| {
+    parse_timeout_overrides(&std::env::var("FETCH_TIMEOUT_OVERRIDES").unwrap_or_default())
+});
+
+// the ranges of ZIDs (first, last) with their timeout, invalid entries are skipped
+fn parse_timeout_overrides(overrides: &str) -> Vec<(u64, u64, Duration)> {
+    let zid = |z: &str| z.trim().strip_prefix('Z')?.parse::<u64>().ok();
+    overrides
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let parsed = entry.split_once('=').and_then(|(zids, timeout)| {
+                let (first, last) = zids.split_once('-').unwrap_or((zids, zids));
+                Some((
+                    zid(first)?,
+                    zid(last)?,
+                    Duration::from_secs_f64(timeout.trim().parse().ok()?),
+                ))
+            });
+            if parsed.is_none() {
+                warn!("invalid entry of FETCH_TIMEOUT_OVERRIDES: {}", entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+// the timeout of the first range of ZIDs containing z_number
+fn timeout_override(overrides: &[(u64, u64, Duration)], z_number: &str) -> Option<Duration> {
+    let n = z_number
+        .strip_prefix('Z')
+        .and_then(|n| n.parse::<u64>().ok())?;
+    overrides
+        .iter()
+        .find(|(first, last, _)| (*first..=*last).contains(&n))
+        .map(|(_, _, timeout)| *timeout)
+}
+
+fn fetch_timeout(z_number: &str) -> Duration {
+    timeout_override(&FETCH_TIMEOUT_OVERRIDES, z_number).unwrap_or(*FETCH_TIMEOUT)
+}
+
+// Network errors (not schema errors) are retried, with an exponential backoff from 200ms,
+// up to FETCH_ATTEMPTS attempts in total (default 3)
+static FETCH_ATTEMPTS: Lazy<u32> = Lazy::new(|| {
+    std::env::var("FETCH_ATTEMPTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(3)
+});
+const FETCH_BACKOFF: Duration = Duration::from_millis(200);
+
+// At most MAX_CONCURRENT_FETCHES requests to wikifunctions are in flight at the same time (default 32),
+// whatever the size of the documents, the other fetches wait for a permit
+static FETCH_PERMITS: Lazy<Semaphore> = Lazy::new(|| {
+    let max = std::env::var("MAX_CONCURRENT_FETCHES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(32usize);
+    Semaphore::new(max.max(1))
+});
+
+async fn fetch_permit() -> std::result::Result<tokio::sync::SemaphorePermit<'static>, MyError> {
+    FETCH_PERMITS
+        .acquire()
+        .await
+        .map_err(|e| MyError::NetworkError(e.to_string()))
+}
+
+async fn _fetch(key: FetchKey) -> std::result::Result<Value, MyError> {
+    let mut attempt = 1;
+    loop {
+        match _fetch_once(&**ENDPOINT, DOMAIN, key.clone()).await {
+            Err(MyError::NetworkError(e)) if attempt < *FETCH_ATTEMPTS => {
+                let backoff = FETCH_BACKOFF * 2u32.pow(attempt - 1);
+                debug!(
+                    "retrying {} in {:?} after network error: {}",
+                    key.z_number, backoff, e
+                );
+                actix_web::rt::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+async fn _fetch_once(
+    endpoint: &dyn Endpoint,
+    domain: &str,
+    key: FetchKey,
+) -> std::result::Result<Value, MyError> {
+    let url = format!("{}{}", domain, endpoint.url(&key));
+    let z_number = key.z_number;
+    // the parameters for the next page, given by the previous response
+    let mut continue_params: Vec<(String, String)> = Vec::new();
+    for _ in 0..=MAX_CONTINUE {
+        debug!("fetching from wikifunction: {}", z_number);
+        // (parse_with_params would add an empty query "?" to the url of the first page)
+        let page_url = match continue_params.as_slice() {
+            [] => reqwest::Url::parse(&url),
+            params => reqwest::Url::parse_with_params(&url, params),
+        }
+        .map_err(|e| MyError::NetworkError(e.to_string()))?;
+        let _permit = fetch_permit().await?;
+        let req = CLIENT.get(page_url).timeout(fetch_timeout(&z_number));
+        let res = match req.send().await {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("error fetching {}: {}", z_number, e);
+                return Err(MyError::NetworkError(e.to_string()));
+            }
+        };
+        debug!("fetched from wikifunction: {}", z_number);
+        // the body may fail to be read, e.g. truncated by an intermediary
+        let body = match res.text().await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("error reading response for {}: {}", z_number, e);
+                return Err(MyError::NetworkError(e.to_string()));
+            }
+        };
+        let res = serde_json::from_str::<Value>(&body).map_err(|_e| {
+            MyError::SchemaError("failed parsing wikifunction response".to_string())
+        })?;
+        match endpoint.parse(&z_number, res)? {
+            Page::ZObject(zobject) => return Ok(zobject),
+            Page::Continue(params) => {
+                debug!("fetching next page from wikifunction: {}", z_number);
+                continue_params = params;
+            }
+        }
+    }
+    Err(MyError::SchemaError(format!(
+        "too many pages in wikifunction response for {}",
+        z_number
+    )))
+}
+
+pub type BoxedFetch =
+    Pin<Box<dyn Future<Output = std::result::Result<Value, MyError>> + std::marker::Send>>;
+type FetchFuture = Shared<BoxedFetch>;
+// how the cache fetches a ZObject it is missing, _fetch() but for tests
+type Fetcher = fn(FetchKey) -> BoxedFetch;
+
+// Fetches in flight, shared by all requests waiting on the same ZID.
+// We only keep weak handles here: once every request waiting on a fetch has been dropped
+// (e.g. the client disconnected), the fetch itself is dropped, releasing its connection,
+// instead of being kept half-done by the cache until expiry.
+static IN_FLIGHT: Lazy<Mutex<HashMap<FetchKey, WeakShared<BoxedFetch>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn in_flight(key: FetchKey, fetcher: Fetcher) -> FetchFuture {
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    if let Some(fut) = in_flight.get(&key).and_then(|fut| fut.upgrade()) {
+        return fut;
+    }
+    // forget about fetches that are finished or abandoned
+    in_flight.retain(|_, fut| fut.upgrade().is_some());
+    let fut = fetcher(key.clone()).shared();
+    if let Some(weak) = fut.downgrade() {
+        in_flight.insert(key, weak);
+    }
+    fut
+}
+
+// Cache of the fetched ZObjects, only the successful results are cached
+// (concurrent fetches are deduplicated by in_flight()).
+// Entries expire after CACHE_TTL_SECS seconds (default 600), plus a random jitter of up to
+// FETCH_CACHE_JITTER seconds (default 60), so entries cached at the same time don't all expire together.
+// For FETCH_CACHE_GRACE seconds after expiry (default 60), the stale entry is still returned,
+// while a single refresh is fetched in the background.
+// The cache holds at most CACHE_MAX_ENTRIES entries (default 10000), beyond which
+// the least recently used entries are evicted.
+struct CacheEntry {
+    value: Value,
+    expires: Instant,
+    refreshing: bool,
+}
+
+static FORM_CACHE: Lazy<Mutex<LruMap<FetchKey, CacheEntry>>> =
+    Lazy::new(|| Mutex::new(LruMap::default()));
+
+fn env_secs(name: &str, default: u64) -> Duration {
+    std::env::var(name)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs_f64)
+        .unwrap_or(Duration::from_secs(default))
+}
+
+// FETCH_CACHE_TTL is the former name of CACHE_TTL_SECS
+static FETCH_CACHE_TTL: Lazy<Duration> = Lazy::new(|| match std::env::var("CACHE_TTL_SECS") {
+    Ok(_) => env_secs("CACHE_TTL_SECS", 600),
+    Err(_) => env_secs("FETCH_CACHE_TTL", 600),
+});
+static FETCH_CACHE_JITTER: Lazy<Duration> = Lazy::new(|| env_secs("FETCH_CACHE_JITTER", 60));
+static FETCH_CACHE_GRACE: Lazy<Duration> = Lazy::new(|| env_secs("FETCH_CACHE_GRACE", 60));
+static CACHE_MAX_ENTRIES: Lazy<usize> = Lazy::new(|| {
+    std::env::var("CACHE_MAX_ENTRIES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(10000)
+});
+
+fn cache_entry(value: Value) -> CacheEntry {
+    // random enough to spread the expiries, without a dependency on rand
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    let jitter = FETCH_CACHE_JITTER.mul_f64((random % 1000) as f64 / 1000.0);
+    CacheEntry {
+        value,
+        expires: Instant::now() + *FETCH_CACHE_TTL + jitter,
+        refreshing: false,
+    }
+}
+
+fn cache_insert(cache: &mut LruMap<FetchKey, CacheEntry>, fetch_key: FetchKey, value: Value) {
+    cache.insert(fetch_key, cache_entry(value));
+    if cache.len() <= *CACHE_MAX_ENTRIES {
+        return;
+    }
+    // the entries past their grace period go first, they can't be served anymore
+    let now = Instant::now();
+    cache.retain(|_, entry| now < entry.expires + *FETCH_CACHE_GRACE);
+    while cache.len() > *CACHE_MAX_ENTRIES {
+        cache.pop_lru();
+    }
+}
+
+fn refresh_in_background(fetch_key: FetchKey, fetcher: Fetcher) {
+    actix_web::rt::spawn(async move {
+        let res = in_flight(fetch_key.clone(), fetcher).await;
+        let mut cache = FORM_CACHE.lock().unwrap();
+        match res {
+            Ok(value) => cache_insert(&mut cache, fetch_key, value),
+            // the stale entry is kept, to be refreshed again by a later fetch
+            Err(_) => {
+                if let Some(entry) = cache.get_mut(&fetch_key) {
+                    entry.refreshing = false;
+                }
+            }
+        }
+    });
+}
+
+async fn fetch_form(fetch_key: FetchKey) -> std::result::Result<Value, MyError> {
+    cached_fetch(fetch_key, |key| _fetch(key).boxed()).await
+}
+
+async fn cached_fetch(
+    fetch_key: FetchKey,
+    fetcher: Fetcher,
+) -> std::result::Result<Value, MyError> {
+    wait_for_batch(&fetch_key).await;
+    if let Some(entry) = FORM_CACHE.lock().unwrap().get_mut(&fetch_key) {
+        let now = Instant::now();
+        if now < entry.expires {
+            return Ok(entry.value.clone());
+        }
+        if now < entry.expires + *FETCH_CACHE_GRACE {
+            if !entry.refreshing {
+                entry.refreshing = true;
+                refresh_in_background(fetch_key, fetcher);
+            }
+            return Ok(entry.value.clone());
+        }
+    }
+    let value = in_flight(fetch_key.clone(), fetcher).await?;
+    cache_insert(&mut FORM_CACHE.lock().unwrap(), fetch_key, value.clone());
+    Ok(value)
+}
+
+// number of ZIDs fetched in one request when priming the cache
+const BATCH_FETCH_SIZE: usize = 50;
+
+async fn fetch_batch(z_numbers: &[String]) -> std::result::Result<(), MyError> {
+    let url = match ENDPOINT.batch_url(z_numbers) {
+        Some(url) => format!("{}{}", DOMAIN, url),
+        None => return Ok(()),
+    };
+    debug!("fetching {} ZIDs from wikifunction", z_numbers.len());
+    let _permit = fetch_permit().await?;
+    let res = CLIENT
+        .get(url)
+        .timeout(*FETCH_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| MyError::NetworkError(e.to_string()))?
+        .text()
+        .await
+        .map_err(|e| MyError::NetworkError(e.to_string()))?;
+    let res = serde_json::from_str::<Value>(&res)
+        .map_err(|_e| MyError::SchemaError("failed parsing wikifunction response".to_string()))?;
+    let mut cache = FORM_CACHE.lock().unwrap();
+    for (z_number, zobject) in ENDPOINT.parse_batch(res) {
+        cache_insert(&mut cache, FetchKey::canonical(z_number), zobject);
+    }
+    Ok(())
+}
+
+// The batches started by prime_cache() and not finished yet, by the ZIDs they fetch:
+// fetch_form() waits for the batch of a ZID, instead of fetching it on its own at the same time.
+// The batches run in the background, labelize doesn't wait for all of them to start labelling
+type BatchFuture = Shared<Pin<Box<dyn Future<Output = ()> + Send>>>;
+static PENDING_BATCHES: Lazy<Mutex<HashMap<FetchKey, BatchFuture>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Fewer ZIDs than this (default 2) are not worth the latency of a batch,
+// they are fetched one by one right away; configured with env BATCH_FETCH_MIN
+static BATCH_FETCH_MIN: Lazy<usize> = Lazy::new(|| {
+    std::env::var("BATCH_FETCH_MIN")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(2)
+});
+
+// the groups of ZIDs fetched together, none when there are too few of them
+fn batches(missing: &[String]) -> Vec<Vec<String>> {
+    if missing.len() < *BATCH_FETCH_MIN {
+        return Vec::new();
+    }
+    missing
+        .chunks(BATCH_FETCH_SIZE)
+        .map(<[_]>::to_vec)
+        .collect()
+}
+
+fn start_batch(z_numbers: Vec<String>, batch: impl Future<Output = ()> + Send + 'static) {
+    let batch = batch.boxed().shared();
+    let mut pending = PENDING_BATCHES.lock().unwrap();
+    for z_number in z_numbers {
+        pending.insert(FetchKey::canonical(z_number), batch.clone());
+    }
+    actix_web::rt::spawn(async move {
+        batch.clone().await;
+        PENDING_BATCHES
+            .lock()
+            .unwrap()
+            .retain(|_, batch| batch.peek().is_none());
+    });
+}
+
+async fn wait_for_batch(fetch_key: &FetchKey) {
+    let batch = PENDING_BATCHES.lock().unwrap().get(fetch_key).cloned();
+    if let Some(batch) = batch {
+        batch.await;
+    }
+}
+
+// Fetch the ZIDs not cached yet in grouped requests, so that fetch() then finds them in the cache,
+// instead of fetching them one by one. ZIDs that failed are simply fetched one by one later
+async fn prime_cache(z_numbers: Vec<String>) {
+    let now = Instant::now();
+    let missing: Vec<String> = {
+        let cache = FORM_CACHE.lock().unwrap();
+        let pending = PENDING_BATCHES.lock().unwrap();
+        z_numbers
+            .into_iter()
+            .filter(|z| {
+                let key = FetchKey::canonical(z.clone());
+                !pending.contains_key(&key)
+                    && cache.get(&key).is_none_or(|entry| entry.expires <= now)
+            })
+            .collect()
+    };
+    for batch in batches(&missing) {
+        start_batch(batch.clone(), async move {
+            if let Err(err) = fetch_batch(&batch).await {
+                warn!("error when fetching a batch of ZIDs: {:?}", err);
+            }
+        });
+    }
+}
+
+// the canonical form of the ZObject
+async fn fetch(z_number: String) -> std::result::Result<Value, MyError> {
+    fetch_form(FetchKey::canonical(z_number)).await
+}
+
+// Where labelize gets the ZObjects from: wikifunctions by default,
+// or any other source (e.g. fixtures in memory) to labelize without network
+pub trait LabelSource: Send + Sync {
+    fn fetch(&self, z_number: String) -> BoxedFetch;
+    // called with all the ZIDs of a document before they are fetched one by one,
+    // e.g. to fetch them in fewer requests
+    fn prime(&self, _z_numbers: Vec<String>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        future::ready(()).boxed()
+    }
+    // whether the labels from this source are kept in LABEL_CACHE, shared by all requests
+    fn cache_labels(&self) -> bool {
+        false
+    }
+}
+
+pub type Source = Arc<dyn LabelSource>;
+
+// the configured wikifunctions endpoint, through the cache of fetch()
+pub struct Wikifunctions;
+
+impl LabelSource for Wikifunctions {
+    fn fetch(&self, z_number: String) -> BoxedFetch {
+        fetch(z_number).boxed()
+    }
+
+    fn prime(&self, z_numbers: Vec<String>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        prime_cache(z_numbers).boxed()
+    }
+
+    fn cache_labels(&self) -> bool {
+        true
+    }
+}
+
+// ZObjects read from a local dump instead of wikifunctions, e.g. offline, or as a fake in tests:
+// {ZID: ZObject}, the ZObjects being the persistent objects (Z2) in canonical form
+pub struct Dump(HashMap<String, Value>);
+
+impl From<HashMap<String, Value>> for Dump {
+    fn from(zobjects: HashMap<String, Value>) -> Self {
+        Dump(zobjects)
+    }
+}
+
+impl Dump {
+    pub fn from_file(path: &str) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        match serde_json::from_str(&text) {
+            Ok(Value::Object(o)) => Ok(Dump(o.into_iter().collect())),
+            Ok(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the dump should be a json object {ZID: ZObject}",
+            )),
+            Err(err) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
         }
     }
 }
 
-// https://github.com/jaemk/cached/issues/81
-#[cached(time = 600)]
-fn fetch(
+impl LabelSource for Dump {
+    fn fetch(&self, z_number: String) -> BoxedFetch {
+        let res = match self.0.get(&z_number) {
+            Some(zobject) => Ok(zobject.clone()),
+            None => Err(MyError::NotFound(format!(
+                "{} is not in the dump",
+                z_number
+            ))),
+        };
+        future::ready(res).boxed()
+    }
+}
+
+// ZObjects read from the files Zxxx.json of a directory, e.g. a downloaded dump.
+// A file is either the ZObject itself, or the response of the API for it,
+// from which the ZObject is extracted as when fetching it (the "data" of the ZID)
+pub struct FileSource {
+    dir: std::path::PathBuf,
+}
+
+impl FileSource {
+    pub fn new(dir: impl Into<std::path::PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn read(&self, z_number: &str) -> std::result::Result<Value, MyError> {
+        // only ZIDs, not arbitrary paths
+        if !ZID.is_match(z_number) {
+            return Err(MyError::NotFound(format!("{} is not a ZID", z_number)));
+        }
+        let path = self.dir.join(format!("{}.json", z_number));
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(MyError::NotFound(format!("no file {}", path.display())))
+            }
+            Err(err) => {
+                return Err(MyError::NetworkError(format!(
+                    "failed reading {}: {}",
+                    path.display(),
+                    err
+                )))
+            }
+        };
+        let res: Value = serde_json::from_str(&text)
+            .map_err(|_e| MyError::SchemaError(format!("failed parsing {}", path.display())))?;
+        if res.get("query").is_some() {
+            return match ActionQuery.parse(z_number, res)? {
+                Page::ZObject(zobject) => Ok(zobject),
+                Page::Continue(_) => Err(MyError::NotFound(format!(
+                    "no key for self ({}) in {}",
+                    z_number,
+                    path.display()
+                ))),
+            };
+        }
+        match res.get("data") {
+            Some(data) => Ok(data.clone()),
+            None => Ok(res),
+        }
+    }
+}
+
+impl LabelSource for FileSource {
+    fn fetch(&self, z_number: String) -> BoxedFetch {
+        future::ready(self.read(&z_number)).boxed()
+    }
+}
+
+// maximum number of redirects followed when looking up the definition of a key
+const MAX_REDIRECTS: usize = 5;
+
+// A persistent object whose value is only a reference to another ZID is a redirect,
+// e.g. an object that has been renamed / merged into another object
+fn redirect_target(obj: &Value) -> Option<String> {
+    let target = match obj.get("Z2K2")? {
+        Value::String(s) => s.as_str(),
+        Value::Object(o) if o.get("Z1K1") == Some(&Value::String("Z9".to_string())) => {
+            o.get("Z9K1")?.as_str()?
+        }
+        _ => return None,
+    };
+    if ZID.is_match(target) {
+        Some(target.to_string())
+    } else {
+        None
+    }
+}
+
+// fetch the ZObject, following redirects,
+// returning the ZID of the object we ended up at, along with its data
+async fn fetch_following_redirects(
     z_number: String,
-) -> Shared<Pin<Box<dyn Future<Output = std::result::Result<Value, MyError>> + std::marker::Send>>>
-{
-    return _fetch(z_number).boxed().shared();
+    source: &dyn LabelSource,
+) -> std::result::Result<(String, Value), MyError> {
+    let mut z_number = z_number;
+    let mut visited = Vec::new();
+    for _ in 0..=MAX_REDIRECTS {
+        let res = source.fetch(z_number.clone()).await?;
+        match redirect_target(&res) {
+            Some(target) if visited.contains(&target) || target == z_number => {
+                return Err(MyError::SchemaError(format!(
+                    "redirect cycle when fetching {}",
+                    z_number
+                )))
+            }
+            Some(target) => {
+                debug!("following redirect from {} to {}", z_number, target);
+                visited.push(std::mem::replace(&mut z_number, target));
+            }
+            None => return Ok((z_number, res)),
+        }
+    }
+    Err(MyError::SchemaError(format!(
+        "too many redirects when fetching {}",
+        z_number
+    )))
+}
+
+// The signature of a function: a Z8 with only its argument declarations (Z8K1) and return type (Z8K2),
+// None if the ZID could not be fetched, or is not a function
+pub async fn fetch_signature(z_number: String, source: &dyn LabelSource) -> Option<Value> {
+    let (z_number, obj) = match fetch_following_redirects(z_number, source).await {
+        Ok(res) => res,
+        Err(e) => {
+            warn!("failed fetching signature: {}", e);
+            return None;
+        }
+    };
+    let function = obj.get("Z2K2")?;
+    if function.get("Z1K1")? != "Z8" {
+        debug!("{} is not a function", z_number);
+        return None;
+    }
+    Some(json!({
+        "Z1K1": "Z8",
+        "Z8K1": function.get("Z8K1")?,
+        "Z8K2": function.get("Z8K2")?,
+    }))
+}
+
+const ENGLISH: &str = "Z1002";
+
+// English labels of well-known global keys, used instead of searching for their declaration,
+// extended (or overridden) with env KEY_LABELS, e.g. KEY_LABELS="Z14K2=composition,Z14K3=code"
+static WELL_KNOWN_KEYS: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    [
+        ("Z1K1", "type"),
+        ("Z2K1", "id"),
+        ("Z2K2", "value"),
+        ("Z2K3", "label"),
+        ("Z2K4", "aliases"),
+        ("Z6K1", "string value"),
+        ("Z9K1", "reference id"),
+        ("Z11K1", "language"),
+        ("Z11K2", "text"),
+        ("Z12K1", "texts"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .chain(
+        std::env::var("KEY_LABELS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|entry| entry.split_once('='))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string())),
+    )
+    .collect()
+});
+
+// maximum depth searched into Z2K2 for the declaration of a key
+const MAX_KEY_SEARCH_DEPTH: usize = 5;
+
+// the type may also be an expanded reference {Z1K1: Z9, Z9K1: Z12}
+fn is_multilingual_text(v: &Value) -> bool {
+    match v.get("Z1K1") {
+        Some(Value::String(t)) => t == "Z12",
+        Some(t @ Value::Object(_)) => {
+            t.get("Z1K1").and_then(Value::as_str) == Some("Z9")
+                && t.get("Z9K1").and_then(Value::as_str) == Some("Z12")
+        }
+        _ => false,
+    }
+}
+
+// Search for the object declaring the key ZxxxKyyy, which
+// - has a string value of matching ZxxxKyyy,
+//   or has an object value, which has a string value of matching ZxxxKyyy (one level of indirection)
+// - has a label, a Multilingual Text (Z12) value
+// Where the declarations are nested differs between types of objects, e.g.
+// - Z4K1 in Z4 (Type): obj["Z2K2"]["Z4K2"][k_number]
+// - Z8K1 in Z8 (Function): obj["Z2K2"]["Z8K1"][k_number]
+// so we search the whole value, up to a limited depth
+fn find_key_declaration<'a>(
+    v: &'a Value,
+    key: &str,
+    depth: usize,
+) -> Option<&'a serde_json::Map<String, Value>> {
+    if depth > MAX_KEY_SEARCH_DEPTH {
+        return None;
+    }
+    match v {
+        Value::Array(arr) => arr
+            .iter()
+            .find_map(|x| find_key_declaration(x, key, depth + 1)),
+        Value::Object(o) => {
+            let declares_key = o.iter().any(|(_k, v)| match v {
+                Value::String(vs) => vs == key,
+                Value::Object(vo) => vo.iter().any(|(_k, vv)| vv.as_str() == Some(key)),
+                _ => false,
+            });
+            if declares_key && o.values().any(is_multilingual_text) {
+                Some(o)
+            } else {
+                o.values()
+                    .find_map(|x| find_key_declaration(x, key, depth + 1))
+            }
+        }
+        _ => None,
+    }
+}
+
+// Some models of monolingual text carry grammatical metadata (e.g. gender) besides Z11K1 and Z11K2,
+// collected as {language ZID: {key: value}} for the texts that have any
+fn text_metadata(texts: &[Value]) -> BTreeMap<String, BTreeMap<String, String>> {
+    texts
+        .iter()
+        .skip(1)
+        .filter_map(|v| {
+            let lang = v.get("Z11K1")?.as_str()?;
+            let metadata: BTreeMap<String, String> = v
+                .as_object()?
+                .iter()
+                .filter(|(k, _v)| !["Z1K1", "Z11K1", "Z11K2"].contains(&k.as_str()))
+                .map(|(k, v)| match v {
+                    Value::String(s) => (k.clone(), s.clone()),
+                    _ => (k.clone(), v.to_string()),
+                })
+                .collect();
+            (!metadata.is_empty()).then(|| (lang.to_string(), metadata))
+        })
+        .collect()
 }
 
-async fn _labelize(s: String) -> std::result::Result<StringType, MyError> {
+async fn _labelize(
+    s: String,
+    source: &dyn LabelSource,
+) -> std::result::Result<StringType, MyError> {
     trace!("labelize {}", s);
-    if Regex::new(r"^Z\d+$").unwrap().is_match(&s) {
-        let readable_labels = fetch(s.clone())
-            .await?
+    if ZID.is_match(&s) {
+        let res = source.fetch(s.clone()).await?;
+        let texts = res
             .get("Z2K3")
             .ok_or(MyError::SchemaError(
                 "wikifunction response is not a Persistent Object, no Z2K3 key ".to_string(),
@@ -87,7 +925,8 @@ async fn _labelize(s: String) -> std::result::Result<StringType, MyError> {
                 "no Z12K1 (Multilingual Text) key in Persistent Object".to_string(),
             ))?
             .as_array()
-            .ok_or(MyError::SchemaError("Z12K1 is not an array".to_string()))?
+            .ok_or(MyError::SchemaError("Z12K1 is not an array".to_string()))?;
+        let readable_labels = texts
             .iter()
             .skip(1)
             .map(|v| -> std::result::Result<(String, String), MyError> {
@@ -101,7 +940,7 @@ async fn _labelize(s: String) -> std::result::Result<StringType, MyError> {
                         .to_string(),
                     v.get("Z11K2")
                         .ok_or(MyError::SchemaError(
-                            "no key Z11K1 in item of Z12K1".to_string(),
+                            "no key Z11K2 in item of Z12K1".to_string(),
                         ))?
                         .as_str()
                         .ok_or(MyError::SchemaError("value of Z11K2 not a str".to_string()))?
@@ -109,16 +948,24 @@ async fn _labelize(s: String) -> std::result::Result<StringType, MyError> {
                 ))
             })
             .collect::<std::result::Result<_, MyError>>()?;
-        Ok(StringType::LabelledNode(LabelledNode::from(
-            readable_labels,
-            s,
-        )))
-    } else if Regex::new(r"^Z\d+K\d+$").unwrap().is_match(&s) {
-        let pat = s.split('K').collect::<Vec<_>>();
-        let z_number = pat[0];
-        // let k_number = pat[1].parse::<usize>().unwrap();
+        Ok(StringType::LabelledNode(
+            LabelledNode::from(readable_labels, s).with_metadata(text_metadata(texts)),
+        ))
+    } else if GLOBAL_KEY.is_match(&s) {
+        // the most common global keys are labelled from a static table, without fetching
+        if let Some(label) = WELL_KNOWN_KEYS.get(&s) {
+            return Ok(StringType::LabelledNode(LabelledNode::from(
+                BTreeMap::from([(ENGLISH.to_string(), label.clone())]),
+                s,
+            )));
+        }
+        let (z_number, k_number) = s
+            .split_once('K')
+            .ok_or(MyError::SchemaError(format!("{} is not a global key", s)))?;
 
-        let res = fetch(z_number.to_string()).await?;
+        // if Zxxx redirects to Zzzz, the key is defined as ZzzzKyyy in Zzzz instead
+        let (z_number, res) = fetch_following_redirects(z_number.to_string(), source).await?;
+        let key = format!("{}K{}", z_number, k_number);
 
         // example object: Z4, of type Z4
         // example object: Z811, of type Z8
@@ -129,46 +976,33 @@ async fn _labelize(s: String) -> std::result::Result<StringType, MyError> {
         // we have fetched the data for Zxxx
         // first of all, Zxxx is an persistent object because it has a Z-number
         // the label for the keys are always stored in Z2K2: value
-        let label_val = res
-            .get("Z2K2")
-            .ok_or(MyError::SchemaError(
+        let label_val = find_key_declaration(
+            res.get("Z2K2").ok_or(MyError::SchemaError(
                 "wikifunction response is not a Persistent Object, no Z2K2 key ".to_string(),
-            ))?
-            .as_object()
-            .ok_or(MyError::SchemaError(
-                "value of Z2K2 is not object".to_string(),
-            ))?
-            .iter()
-            // we now try to find the key-value in Z2K2, where the value is an array of objects
-            .filter_map(|(_k, v)| v.as_array())
-            .filter(|v| v.len() > 1 && v[1].is_object())
-            // ...and one of the object has string value of matching ZxxxKyyy
-            // or the object has an object value, which has a string value of matching ZxxxKyyy (one level of indirection)
-            .filter_map(|v| {
-                v.iter().filter_map(|x| x.as_object()).find(|o| {
-                    o.iter().any(|(_k, v)| match v {
-                        Value::String(vs) => vs.clone() == s,
-                        Value::Object(vo) => {
-                            vo.iter().any(|(_k, vv)| *vv == Value::String(s.clone()))
-                        }
-                        _ => false,
-                    })
-                })
-            })
-            .next()
-            .unwrap()
-            .iter()
-            .filter_map(|(_k, v)| v.as_object())
-            .find(|o| o.get("Z1K1") == Some(&Value::String("Z12".to_string())))
-            .unwrap();
+            ))?,
+            &key,
+            0,
+        )
+        .ok_or(MyError::SchemaError(format!(
+            "no declaration of key {} found in Z2K2",
+            key
+        )))?
+        .iter()
+        .map(|(_k, v)| v)
+        .find(|v| is_multilingual_text(v))
+        .ok_or(MyError::SchemaError(format!(
+            "no Multilingual Text label in the declaration of key {}",
+            key
+        )))?;
 
-        let readable_labels = label_val
+        let texts = label_val
             .get("Z12K1")
             .ok_or(MyError::SchemaError(
                 "no \"Z12K1\" key in wikifunction response".to_string(),
             ))?
             .as_array()
-            .ok_or(MyError::SchemaError("Z12K1 is not an array".to_string()))?
+            .ok_or(MyError::SchemaError("Z12K1 is not an array".to_string()))?;
+        let readable_labels = texts
             .iter()
             .skip(1)
             .map(|v| -> std::result::Result<(String, String), MyError> {
@@ -180,56 +1014,1126 @@ async fn _labelize(s: String) -> std::result::Result<StringType, MyError> {
                         .as_str()
                         .ok_or(MyError::SchemaError("value of Z11K1 not a str".to_string()))?
                         .to_string(),
-                    format!(
-                        "'{}'",
-                        v.get("Z11K2")
-                            .ok_or(MyError::SchemaError(
-                                "no key Z11K1 in item of Z12K1".to_string(),
-                            ))?
-                            .as_str()
-                            .ok_or(MyError::SchemaError("value of Z11K2 not a str".to_string()))?
-                    ),
+                    v.get("Z11K2")
+                        .ok_or(MyError::SchemaError(
+                            "no key Z11K2 in item of Z12K1".to_string(),
+                        ))?
+                        .as_str()
+                        .ok_or(MyError::SchemaError("value of Z11K2 not a str".to_string()))?
+                        .to_string(),
                 ))
             })
             .collect::<std::result::Result<_, MyError>>()?;
-        Ok(StringType::LabelledNode(LabelledNode::from(
-            readable_labels,
-            s,
-        )))
+        Ok(StringType::LabelledNode(
+            LabelledNode::from(readable_labels, s).with_metadata(text_metadata(texts)),
+        ))
     } else {
         Ok(StringType::String(s))
     }
 }
 
-async fn _labelize_wrapped(s: String) -> StringType {
+// Strings matching this regex are never labelized, and kept as is without fetching,
+// e.g. ZIDs known not to exist in this deployment.
+// configured with env LABELIZE_BYPASS, e.g. LABELIZE_BYPASS="^Z9\d{5}$|^https?://"
+static BYPASS: Lazy<Option<Regex>> = Lazy::new(|| {
+    let pattern = std::env::var("LABELIZE_BYPASS").ok()?;
+    match Regex::new(&pattern) {
+        Ok(re) => Some(re),
+        Err(e) => {
+            warn!("invalid LABELIZE_BYPASS {}: {}", pattern, e);
+            None
+        }
+    }
+});
+
+// The outcome of labelizing each string, including the fallbacks to the string as is
+// (e.g. ZIDs whose object doesn't have the expected shape) and the ZIDs not found,
+// so that they aren't fetched and parsed again by every request.
+// Network errors are not cached, the next request tries again.
+// Only the strings that are ZIDs or keys are cached, not the other strings of the documents.
+// The cached labels are shared by requests with different langs: they keep all their languages,
+// unless RETAIN_REQUESTED_LANGS, where they are cached trimmed, by string and retained languages.
+// Entries expire after LABEL_CACHE_TTL_SECS seconds (default 600),
+// and at most CACHE_MAX_ENTRIES entries are kept, the least recently used are evicted.
+type LabelKey = (String, Option<Vec<String>>);
+static LABEL_CACHE: Lazy<Mutex<LruMap<LabelKey, (StringType, Instant)>>> =
+    Lazy::new(|| Mutex::new(LruMap::default()));
+static LABEL_CACHE_TTL: Lazy<Duration> = Lazy::new(|| env_secs("LABEL_CACHE_TTL_SECS", 600));
+
+fn label_key(s: &str, retain: Option<&[String]>) -> LabelKey {
+    (s.to_string(), retain.map(<[_]>::to_vec))
+}
+
+fn cached_label(s: &str, retain: Option<&[String]>) -> Option<StringType> {
+    match LABEL_CACHE.lock().unwrap().get_mut(&label_key(s, retain)) {
+        Some((label, expires)) if Instant::now() < *expires => Some(label.clone()),
+        _ => None,
+    }
+}
+
+fn cache_label(s: &str, retain: Option<&[String]>, label: StringType) {
+    if !ZID_OR_KEY.is_match(s.trim()) {
+        return;
+    }
+    let now = Instant::now();
+    let mut cache = LABEL_CACHE.lock().unwrap();
+    cache.insert(label_key(s, retain), (label, now + *LABEL_CACHE_TTL));
+    if cache.len() <= *CACHE_MAX_ENTRIES {
+        return;
+    }
+    cache.retain(|_, (_, expires)| now < *expires);
+    while cache.len() > *CACHE_MAX_ENTRIES {
+        cache.pop_lru();
+    }
+}
+
+// the label of a string, with only the languages of retain if given
+async fn _labelize_wrapped(s: String, source: Source, retain: Option<&[String]>) -> StringType {
     trace!("labelize wrapped {}", s);
     if s.is_empty() {
         return StringType::String(s);
     }
-    match _labelize(s.clone()).await {
+    if BYPASS.as_ref().is_some_and(|re| re.is_match(&s)) {
+        trace!("bypass labelize {}", s);
+        return StringType::String(s);
+    }
+    if source.cache_labels() {
+        if let Some(label) = cached_label(&s, retain) {
+            return label;
+        }
+    }
+    // ZIDs are looked up without surrounding whitespace, the input string is kept as the original
+    let label = match _labelize(s.trim().to_string(), source.as_ref()).await {
+        Ok(StringType::LabelledNode(n)) if s.trim() != s => {
+            StringType::LabelledNode(n.with_original(s.clone()))
+        }
+        Ok(StringType::String(_)) => StringType::String(s.clone()),
         Ok(out) => out,
+        // marked as unresolved, to tell apart ZIDs that don't exist, e.g. typos
+        Err(MyError::NotFound(err)) => {
+            debug!("{} not found: {}", s, err);
+            StringType::LabelledNode(LabelledNode::unresolved(s.clone()))
+        }
+        Err(MyError::NetworkError(err)) => {
+            warn!("network error when labelizing {}: {}", s, err);
+            return StringType::String(s);
+        }
         Err(err) => {
             warn!("error when parsing {}: {:?}", s, err);
-            StringType::String(s)
+            StringType::String(s.clone())
         }
+    };
+    let label = match retain {
+        Some(langs) => label.retain_langs(langs),
+        None => label,
+    };
+    if source.cache_labels() {
+        cache_label(&s, retain, label.clone());
     }
+    label
+}
+
+// the contents of a Quote (Z99) are literal data, and should not be labelized
+fn is_quote(o: &serde_json::Map<String, Value>) -> bool {
+    match o.get("Z1K1") {
+        Some(Value::String(s)) => s == "Z99",
+        // the type could also be an expanded reference to Z99
+        Some(Value::Object(typ)) => typ.get("Z9K1") == Some(&Value::String("Z99".to_string())),
+        _ => false,
+    }
+}
+
+// Keys starting with one of these prefixes are metadata of other tooling, not part of the ZObject:
+// the key and its whole value are passed through as is, without labelizing.
+// configured with env PASSTHROUGH_KEY_PREFIXES, comma separated, default "@"
+static PASSTHROUGH_KEY_PREFIXES: Lazy<Vec<String>> = Lazy::new(|| {
+    std::env::var("PASSTHROUGH_KEY_PREFIXES")
+        .unwrap_or_else(|_| "@".to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|prefix| !prefix.is_empty())
+        .map(|prefix| prefix.to_string())
+        .collect()
+});
+
+fn is_passthrough_key(key: &str) -> bool {
+    PASSTHROUGH_KEY_PREFIXES
+        .iter()
+        .any(|prefix| key.starts_with(prefix.as_str()))
+}
+
+// convert to SimpleValue as is, without fetching any labels
+fn quoted(v: Value) -> SimpleValue {
+    match v {
+        scalar @ (Value::Null | Value::Bool(_) | Value::Number(_)) => {
+            SimpleValue::Scalar(scalar.to_string())
+        }
+        Value::String(s) => SimpleValue::StringType(StringType::String(s)),
+        Value::Array(a) => SimpleValue::Array(a.into_iter().map(quoted).collect()),
+        Value::Object(o) => SimpleValue::Object(
+            o.into_iter()
+                .map(|(key, val)| (StringType::String(key), quoted(val)))
+                .collect(),
+        ),
+    }
+}
+
+// With env RETAIN_REQUESTED_LANGS=true, labels only keep the languages of the request
+// (or their first label if they have none of them), the other languages are dropped when parsed,
+// capping the memory used by objects translated in hundreds of languages
+static RETAIN_REQUESTED_LANGS: Lazy<bool> =
+    Lazy::new(|| std::env::var("RETAIN_REQUESTED_LANGS").as_deref() == Ok("true"));
+
+// Labels built during one request, shared by all occurrences of the same string in the document,
+// so that a ZID referenced many times is parsed into a LabelledNode only once
+type LabelFuture = Shared<Pin<Box<dyn Future<Output = StringType> + std::marker::Send>>>;
+
+// Limits of the labelization of one request, beyond which ZIDs are left unlabelled,
+// and the result marked as incomplete.
+// configured with env LABELIZE_TIMEOUT (seconds) and MAX_ZIDS_PER_REQUEST, no limit by default
+static LABELIZE_TIMEOUT: Lazy<Option<Duration>> = Lazy::new(|| {
+    std::env::var("LABELIZE_TIMEOUT")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs_f64)
+});
+static MAX_ZIDS_PER_REQUEST: Lazy<Option<usize>> = Lazy::new(|| {
+    std::env::var("MAX_ZIDS_PER_REQUEST")
+        .ok()
+        .and_then(|s| s.parse().ok())
+});
+
+struct Memo {
+    source: Source,
+    // with "labelize": false, strings are kept as is without fetching
+    no_labels: bool,
+    labels: Mutex<HashMap<String, LabelFuture>>,
+    // the languages kept in the labels, all of them if None
+    retain: Option<Vec<String>>,
+    deadline: Option<Instant>,
+    // number of distinct ZIDs labelized so far
+    zids: Mutex<usize>,
+    // why some ZIDs were left unlabelled, shared with the label futures
+    incomplete: Arc<Mutex<Option<Incomplete>>>,
+    // ZIDs that redirect to another ZID carry the label of the final target
+    resolve_aliases: bool,
+}
+
+impl Memo {
+    fn new(fmt: &LabelFormat, source: Source) -> Self {
+        Self {
+            source,
+            no_labels: fmt.no_labels,
+            labels: Mutex::new(HashMap::new()),
+            retain: RETAIN_REQUESTED_LANGS.then(|| {
+                fmt.required_lang
+                    .iter()
+                    .chain(fmt.langs.iter())
+                    .cloned()
+                    .chain(std::iter::once(fmt.fallback_lang().to_string()))
+                    .collect()
+            }),
+            deadline: LABELIZE_TIMEOUT.map(|timeout| Instant::now() + timeout),
+            zids: Mutex::new(0),
+            incomplete: Arc::new(Mutex::new(None)),
+            resolve_aliases: fmt.resolve_aliases,
+        }
+    }
+
+    // record why the result is incomplete into the request's LabelFormat, the first reason is kept
+    fn finish(self, fmt: &LabelFormat) {
+        if let Some(reason) = *self.incomplete.lock().unwrap() {
+            fmt.incomplete.lock().unwrap().get_or_insert(reason);
+        }
+    }
+}
+
+// A ZID that only redirects to another ZID (a thin alias) carries the label of the final target,
+// the redirects are followed up to MAX_REDIRECTS hops
+async fn with_alias_target(
+    label: StringType,
+    s: String,
+    source: Source,
+    retain: Option<&[String]>,
+) -> StringType {
+    if !matches!(label, StringType::LabelledNode(_)) || !ZID.is_match(&s) {
+        return label;
+    }
+    match fetch_following_redirects(s.clone(), source.as_ref()).await {
+        Ok((target, _res)) if target != s => {
+            let target = _labelize_wrapped(target, source, retain).await;
+            label.with_redirect(target)
+        }
+        Ok(_) => label,
+        Err(err) => {
+            warn!("error when following redirects of {}: {:?}", s, err);
+            label
+        }
+    }
+}
+
+fn labelize_memoized(s: String, memo: &Memo) -> LabelFuture {
+    let retain = memo.retain.clone();
+    let deadline = memo.deadline;
+    let incomplete = memo.incomplete.clone();
+    let source = memo.source.clone();
+    let resolve_aliases = memo.resolve_aliases;
+    memo.labels
+        .lock()
+        .unwrap()
+        .entry(s.clone())
+        .or_insert_with(|| {
+            if memo.no_labels {
+                return future::ready(StringType::String(s)).boxed().shared();
+            }
+            if ZID_OR_KEY.is_match(&s) {
+                let mut zids = memo.zids.lock().unwrap();
+                *zids += 1;
+                if MAX_ZIDS_PER_REQUEST.is_some_and(|max| *zids > max) {
+                    incomplete.lock().unwrap().get_or_insert(Incomplete::ZidCap);
+                    return future::ready(StringType::String(s)).boxed().shared();
+                }
+            }
+            async move {
+                let label = match deadline {
+                    Some(deadline) => {
+                        match actix_web::rt::time::timeout(
+                            deadline.saturating_duration_since(Instant::now()),
+                            _labelize_wrapped(s.clone(), source.clone(), retain.as_deref()),
+                        )
+                        .await
+                        {
+                            Ok(label) => label,
+                            Err(_) => {
+                                incomplete
+                                    .lock()
+                                    .unwrap()
+                                    .get_or_insert(Incomplete::Timeout);
+                                StringType::String(s.clone())
+                            }
+                        }
+                    }
+                    None => _labelize_wrapped(s.clone(), source.clone(), retain.as_deref()).await,
+                };
+                match resolve_aliases {
+                    true => with_alias_target(label, s, source, retain.as_deref()).await,
+                    false => label,
+                }
+            }
+            .boxed()
+            .shared()
+        })
+        .clone()
+}
+
+// the ZID of the object to fetch for a string or a key
+fn zid_to_fetch(s: &str) -> Option<String> {
+    if BYPASS.as_ref().is_some_and(|re| re.is_match(s)) || WELL_KNOWN_KEYS.contains_key(s) {
+        return None;
+    }
+    ZID_OR_KEY.captures(s.trim()).map(|z| z[1].to_string())
+}
+
+// the distinct ZIDs of the objects to fetch for the strings and keys of the document
+fn collect_zids(v: &Value, found: &mut BTreeSet<String>) {
+    match v {
+        Value::String(s) => found.extend(zid_to_fetch(s)),
+        Value::Array(a) => a.iter().for_each(|x| collect_zids(x, found)),
+        Value::Object(o) => {
+            let is_quote = is_quote(o);
+            o.iter()
+                .filter(|(k, _x)| !is_passthrough_key(k))
+                .for_each(|(k, x)| {
+                    found.extend(zid_to_fetch(k));
+                    // the quoted value is kept as is, see quoted()
+                    if !(is_quote && k == "Z99K1") {
+                        collect_zids(x, found)
+                    }
+                })
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+pub async fn labelize(v: Value, fmt: &LabelFormat, source: Source) -> SimpleValue {
+    if !fmt.no_labels {
+        let mut zids = BTreeSet::new();
+        collect_zids(&v, &mut zids);
+        source.prime(zids.into_iter().collect()).await;
+    }
+    let memo = Memo::new(fmt, source);
+    let val = _labelize_json(v, &memo).await;
+    memo.finish(fmt);
+    val
+}
+
+// the label of a single string, e.g. a ZID or a key
+pub async fn labelize_string(s: String, source: Source) -> StringType {
+    _labelize_wrapped(s, source, None).await
+}
+
+// the label of a single string within a request, as found by labelize():
+// under the deadline and the ZID cap, the reason of a missing label being recorded in fmt
+pub async fn labelize_single(s: String, fmt: &LabelFormat, source: Source) -> StringType {
+    let memo = Memo::new(fmt, source);
+    let label = labelize_memoized(s, &memo).await;
+    memo.finish(fmt);
+    label
 }
 
 #[async_recursion]
-pub async fn labelize(v: Value) -> SimpleValue {
+async fn _labelize_json(v: Value, memo: &Memo) -> SimpleValue {
     trace!("_labelize_json {}", v);
     match v {
-        Value::Null => unimplemented!(),
-        Value::Bool(_b) => unimplemented!(),
-        Value::Number(_n) => unimplemented!(),
-        Value::String(s) => SimpleValue::StringType(_labelize_wrapped(s).await),
-        Value::Array(a) => SimpleValue::Array(future::join_all(a.into_iter().map(labelize)).await),
-        Value::Object(o) => SimpleValue::Object(BTreeSet::from_iter(
-            future::join_all(
-                o.into_iter()
-                    .map(|(key, val)| future::join(_labelize_wrapped(key), labelize(val))),
+        scalar @ (Value::Null | Value::Bool(_) | Value::Number(_)) => {
+            SimpleValue::Scalar(scalar.to_string())
+        }
+        Value::String(s) => SimpleValue::StringType(labelize_memoized(s, memo).await),
+        // the type header of a Benjamin array is labelized like any other element,
+        // and stays in place as the first element; see SimpleValue::raw_array_types() to keep it raw
+        Value::Array(a) => SimpleValue::Array(
+            future::join_all(a.into_iter().map(|x| _labelize_json(x, memo))).await,
+        ),
+        Value::Object(o) => {
+            let is_quote = is_quote(&o);
+            SimpleValue::Object(SimpleObject::from_iter(
+                future::join_all(o.into_iter().map(|(key, val)| async move {
+                    if is_passthrough_key(&key) {
+                        (StringType::String(key), quoted(val))
+                    } else if is_quote && key == "Z99K1" {
+                        (labelize_memoized(key, memo).await, quoted(val))
+                    } else {
+                        future::join(labelize_memoized(key, memo), _labelize_json(val, memo)).await
+                    }
+                }))
+                .await,
+            ))
+        }
+    }
+}
+
+// Replace the ZID strings in the json with their labels, and nothing else:
+// the structure, key order, and non-string values are kept exactly as they are
+pub async fn replace_labels(v: OrderedValue, fmt: &LabelFormat, source: Source) -> OrderedValue {
+    if !fmt.no_labels {
+        let mut zids = BTreeSet::new();
+        collect_replaced_zids(&v, &mut zids);
+        source.prime(zids.into_iter().collect()).await;
+    }
+    let memo = Memo::new(fmt, source);
+    let val = _replace_labels(v, fmt, &memo).await;
+    memo.finish(fmt);
+    val
+}
+
+#[async_recursion]
+async fn _replace_labels(v: OrderedValue, fmt: &LabelFormat, memo: &Memo) -> OrderedValue {
+    trace!("replace_labels {:?}", v);
+    match v {
+        OrderedValue::Scalar(Value::String(s)) => {
+            OrderedValue::Scalar(labelize_memoized(s, memo).await.choose_lang(fmt).into())
+        }
+        OrderedValue::Scalar(_) => v,
+        OrderedValue::Array(a) => OrderedValue::Array(
+            future::join_all(a.into_iter().map(|x| _replace_labels(x, fmt, memo))).await,
+        ),
+        // the entries are walked in place, in the order of the input
+        OrderedValue::Object(o) => {
+            let is_quote = is_ordered_quote(&o);
+            OrderedValue::Object(
+                future::join_all(o.into_iter().map(|(key, val)| async move {
+                    if is_passthrough_key(&key) || (is_quote && key == "Z99K1") {
+                        (key, val)
+                    } else {
+                        (key, _replace_labels(val, fmt, memo).await)
+                    }
+                }))
+                .await,
             )
-            .await,
-        )),
+        }
+    }
+}
+
+// is_quote() for the objects of replace_labels()
+fn is_ordered_quote(o: &[(String, OrderedValue)]) -> bool {
+    match o.iter().find(|(k, _v)| k == "Z1K1").map(|(_k, v)| v) {
+        Some(OrderedValue::Scalar(Value::String(s))) => s == "Z99",
+        Some(OrderedValue::Object(typ)) => typ
+            .iter()
+            .any(|(k, v)| k == "Z9K1" && *v == OrderedValue::Scalar(json!("Z99"))),
+        _ => false,
+    }
+}
+
+// the distinct ZIDs of the objects to fetch for the strings replaced by replace_labels(),
+// only the values, the keys are kept as they are
+fn collect_replaced_zids(v: &OrderedValue, found: &mut BTreeSet<String>) {
+    match v {
+        OrderedValue::Scalar(Value::String(s)) => found.extend(zid_to_fetch(s)),
+        OrderedValue::Scalar(_) => {}
+        OrderedValue::Array(a) => a.iter().for_each(|x| collect_replaced_zids(x, found)),
+        OrderedValue::Object(o) => {
+            let is_quote = is_ordered_quote(o);
+            o.iter()
+                .filter(|(k, _x)| !(is_passthrough_key(k) || (is_quote && k == "Z99K1")))
+                .for_each(|(_k, x)| collect_replaced_zids(x, found))
+        }
+    }
+}
+
+// A place where a string occurs in a json document: the JSON pointer (RFC 6901) of the value,
+// or of the object entry when the string is the key of that entry
+#[derive(Debug, Clone)]
+pub struct Occurrence {
+    pub path: String,
+    pub is_key: bool,
+}
+
+fn pointer_token(s: &str) -> String {
+    s.replace('~', "~0").replace('/', "~1")
+}
+
+fn collect_occurrences(v: &Value, path: String, found: &mut BTreeMap<String, Vec<Occurrence>>) {
+    match v {
+        Value::String(s) => found.entry(s.clone()).or_default().push(Occurrence {
+            path,
+            is_key: false,
+        }),
+        Value::Array(a) => a
+            .iter()
+            .enumerate()
+            .for_each(|(i, x)| collect_occurrences(x, format!("{}/{}", path, i), found)),
+        Value::Object(o) => {
+            let is_quote = is_quote(o);
+            o.iter()
+                .filter(|(k, _x)| !is_passthrough_key(k))
+                .for_each(|(k, x)| {
+                    let path = format!("{}/{}", path, pointer_token(k));
+                    found.entry(k.clone()).or_default().push(Occurrence {
+                        path: path.clone(),
+                        is_key: true,
+                    });
+                    // the quoted value is kept as is, see quoted()
+                    if !(is_quote && k == "Z99K1") {
+                        collect_occurrences(x, path, found)
+                    }
+                })
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+// The labels of all the distinct strings in the document, in the order they are resolved,
+// each with the places it occurs in the document, so the structure can be sent before the labels.
+// The strings are labelized as by labelize(): all the ZIDs are primed first,
+// and the timeout, ZID cap, retained languages, and "labelize": false apply the same
+pub fn labels_as_resolved(
+    v: &Value,
+    fmt: &LabelFormat,
+    source: Source,
+) -> impl futures::Stream<Item = (Vec<Occurrence>, StringType)> + Send {
+    let mut zids = BTreeSet::new();
+    if !fmt.no_labels {
+        collect_zids(v, &mut zids);
+    }
+    let prime = source.prime(zids.into_iter().collect());
+    let memo = Memo::new(fmt, source);
+    let mut found = BTreeMap::new();
+    collect_occurrences(v, String::new(), &mut found);
+    // the label futures only start fetching when polled, after the priming
+    let labels = found
+        .into_iter()
+        .map(|(s, occurrences)| labelize_memoized(s, &memo).map(|label| (occurrences, label)))
+        .collect::<futures::stream::FuturesUnordered<_>>();
+    futures::stream::once(prime.map(|()| labels)).flatten()
+}
+
+// Documents nested deeper than MAX_DEPTH arrays and objects (default 100) are rejected,
+// as labelize() and the later stages recurse through every level
+static MAX_DEPTH: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MAX_DEPTH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(100)
+});
+
+pub fn too_deep(v: &Value) -> bool {
+    // iterative, so that the check itself does not recurse as deep as the document
+    let mut stack = vec![(v, 1)];
+    while let Some((v, depth)) = stack.pop() {
+        if depth > *MAX_DEPTH && (v.is_array() || v.is_object()) {
+            return true;
+        }
+        match v {
+            Value::Array(a) => stack.extend(a.iter().map(|x| (x, depth + 1))),
+            Value::Object(o) => stack.extend(o.values().map(|x| (x, depth + 1))),
+            _ => {}
+        }
+    }
+    false
+}
+
+// The numbers, booleans, and nulls in the document, with their path (JSON pointer):
+// every scalar of a canonical ZObject is a string, so these usually are malformed data
+pub fn unexpected_scalars(v: &Value) -> Vec<Value> {
+    fn collect(v: &Value, path: String, found: &mut Vec<Value>) {
+        let typ = match v {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => return,
+            Value::Array(a) => {
+                a.iter()
+                    .enumerate()
+                    .for_each(|(i, x)| collect(x, format!("{}/{}", path, i), found));
+                return;
+            }
+            Value::Object(o) => {
+                o.iter()
+                    .for_each(|(k, x)| collect(x, format!("{}/{}", path, pointer_token(k)), found));
+                return;
+            }
+        };
+        found.push(json!({"path": path, "type": typ}));
+    }
+    let mut found = Vec::new();
+    collect(v, String::new(), &mut found);
+    found
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn quoted_reference() {
+        // the content of a Z99 (Quote) is literal data, not references to labelize
+        let v = json!({"Z1K1": {"Z1K1": "Z9", "Z9K1": "Z99"}, "Z99K1": "Z801"});
+        assert!(is_quote(v.as_object().unwrap()));
+        assert_eq!(
+            quoted(v["Z99K1"].clone()),
+            SimpleValue::StringType(StringType::String("Z801".to_string()))
+        );
+        assert!(!is_quote(json!({"Z1K1": "Z7"}).as_object().unwrap()));
+    }
+
+    #[actix_web::test]
+    async fn replace_keeps_numbers_and_order() {
+        // none of the strings are ZIDs, nothing is fetched
+        let v = r#"{"label":"hello","count":3,"ratio":0.5,"ok":true,"none":null,"a":["world",-1]}"#;
+        let fmt = LabelFormat::from_langs(vec!["Z1002".to_string()]);
+        let v = replace_labels(
+            serde_json::from_str(v).unwrap(),
+            &fmt,
+            Arc::new(Wikifunctions),
+        )
+        .await;
+        assert_eq!(
+            serde_json::to_string(&v).unwrap(),
+            r#"{"label":"hello","count":3,"ratio":0.5,"ok":true,"none":null,"a":["world",-1]}"#
+        );
+    }
+
+    // a source without any ZObject, recording the ZIDs it is primed with
+    #[derive(Default)]
+    struct Priming(Mutex<Vec<String>>);
+
+    impl LabelSource for Priming {
+        fn fetch(&self, z_number: String) -> BoxedFetch {
+            future::ready(Err(MyError::NotFound(z_number))).boxed()
+        }
+
+        fn prime(&self, z_numbers: Vec<String>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+            self.0.lock().unwrap().extend(z_numbers);
+            future::ready(()).boxed()
+        }
+    }
+
+    #[actix_web::test]
+    async fn replace_primes_values() {
+        let v = json!({"Z801K1": ["Z10001", "Z10002K1"], "q": {"Z1K1": "Z99", "Z99K1": "Z10003"}});
+        let source = Arc::new(Priming::default());
+        replace_labels(v.into(), &LabelFormat::default(), source.clone()).await;
+        // neither the keys nor the quoted value
+        assert_eq!(
+            *source.0.lock().unwrap(),
+            vec![
+                "Z10001".to_string(),
+                "Z10002".to_string(),
+                "Z99".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn label_cache_only_zids() {
+        let label = StringType::String("label".to_string());
+        cache_label("Z99999903", None, label.clone());
+        cache_label("not a ZID 99999903", None, label.clone());
+        assert_eq!(cached_label("Z99999903", None), Some(label));
+        assert_eq!(cached_label("not a ZID 99999903", None), None);
+    }
+
+    // Z99999905 labelled in english, french and german, with its labels cached like wikifunctions
+    struct Multilingual;
+
+    impl LabelSource for Multilingual {
+        fn fetch(&self, z_number: String) -> BoxedFetch {
+            let label =
+                |lang: &str, text: &str| json!({"Z1K1": "Z11", "Z11K1": lang, "Z11K2": text});
+            future::ready(Ok(json!({
+                "Z1K1": "Z2",
+                "Z2K1": {"Z1K1": "Z6", "Z6K1": z_number},
+                "Z2K2": "",
+                "Z2K3": {"Z1K1": "Z12", "Z12K1": [
+                    "Z11",
+                    label("Z1002", "three"),
+                    label("Z1004", "trois"),
+                    label("Z1430", "drei")
+                ]}
+            })))
+            .boxed()
+        }
+
+        fn cache_labels(&self) -> bool {
+            true
+        }
+    }
+
+    // the languages of the label of Z99999905, with only the languages of retain
+    async fn retained(retain: Option<&[String]>) -> Vec<String> {
+        match _labelize_wrapped("Z99999905".to_string(), Arc::new(Multilingual), retain).await {
+            StringType::LabelledNode(n) => n.label_langs().cloned().collect(),
+            StringType::String(s) => panic!("{} is not labelled", s),
+        }
+    }
+
+    #[actix_web::test]
+    async fn retained_langs() {
+        let french = ["Z1004".to_string()];
+        assert_eq!(retained(Some(&french)).await, vec!["Z1004"]);
+        // the trimmed label is cached for these languages only
+        assert_eq!(retained(None).await, vec!["Z1002", "Z1004", "Z1430"]);
+        let german = ["Z1430".to_string(), "Z1002".to_string()];
+        assert_eq!(retained(Some(&german)).await, vec!["Z1002", "Z1430"]);
+    }
+
+    // the places of each string of a document, as (string, JSON pointer, is_key)
+    fn occurrences(v: &Value) -> Vec<(String, String, bool)> {
+        let mut found = BTreeMap::new();
+        collect_occurrences(v, String::new(), &mut found);
+        found
+            .into_iter()
+            .flat_map(|(s, occurrences)| {
+                occurrences
+                    .into_iter()
+                    .map(move |o| (s.clone(), o.path, o.is_key))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn occurrences_skip_quoted() {
+        let v = json!({"a/b": ["x", "y"], "q": {"Z1K1": "Z99", "Z99K1": {"Z1K1": "Z6"}}});
+        let place = |s: &str, path: &str, is_key| (s.to_string(), path.to_string(), is_key);
+        assert_eq!(
+            occurrences(&v),
+            vec![
+                place("Z1K1", "/q/Z1K1", true),
+                place("Z99", "/q/Z1K1", false),
+                place("Z99K1", "/q/Z99K1", true),
+                place("a/b", "/a~1b", true),
+                place("q", "/q", true),
+                place("x", "/a~1b/0", false),
+                place("y", "/a~1b/1", false),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn labels_as_resolved_document() {
+        // none of the strings are ZIDs, nothing is fetched
+        let v = json!({"a": ["x", "x"], "b": "x"});
+        let mut labels: Vec<_> =
+            labels_as_resolved(&v, &LabelFormat::default(), Arc::new(Wikifunctions))
+                .map(|(occurrences, label)| (label.into_raw(), occurrences.len()))
+                .collect()
+                .await;
+        labels.sort();
+        let label = |s: &str, n| (s.to_string(), n);
+        assert_eq!(labels, vec![label("a", 1), label("b", 1), label("x", 3)]);
+    }
+
+    #[test]
+    fn batch_threshold() {
+        let zids = |n: usize| (1..=n).map(|i| format!("Z{}", i)).collect::<Vec<_>>();
+        // a single ZID is fetched right away, without waiting for a batch
+        assert!(batches(&zids(1)).is_empty());
+        let sizes: Vec<usize> = batches(&zids(120)).iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![BATCH_FETCH_SIZE, BATCH_FETCH_SIZE, 20]);
+    }
+
+    #[actix_web::test]
+    async fn fetch_waits_for_batch() {
+        // not fetched from the network: the ZID is cached by its batch while fetch() waits for it
+        let z_number = "Z99999901".to_string();
+        let zobject = json!({"Z1K1": "Z2", "Z2K1": {"Z1K1": "Z6", "Z6K1": "Z99999901"}});
+        let batch = {
+            let (z_number, zobject) = (z_number.clone(), zobject.clone());
+            async move {
+                actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+                FORM_CACHE
+                    .lock()
+                    .unwrap()
+                    .insert(FetchKey::canonical(z_number), cache_entry(zobject));
+            }
+        };
+        start_batch(vec![z_number.clone()], batch);
+        assert!(PENDING_BATCHES
+            .lock()
+            .unwrap()
+            .contains_key(&FetchKey::canonical(z_number.clone())));
+        assert_eq!(fetch(z_number).await.unwrap(), zobject);
+    }
+
+    // a fake wikifunctions, answering the requests in turn with the given raw http responses,
+    // returning its url, and the paths requested
+    fn fake_server(responses: Vec<String>) -> (String, Arc<Mutex<Vec<String>>>) {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let requested = paths.clone();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split(' ').nth(1).unwrap_or_default().to_string();
+                requested.lock().unwrap().push(path);
+                // the headers, up to the empty line
+                while !matches!(line.as_str(), "\r\n" | "") {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, paths)
+    }
+
+    fn ok(body: Value) -> String {
+        let body = body.to_string();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    #[actix_web::test]
+    async fn fetch_two_pages() {
+        let (url, paths) = fake_server(vec![
+            ok(json!({
+                "continue": {"wikilambdaload_continue": "Z10001", "continue": "-||"},
+                "query": {"wikilambdaload_zobjects": {}}
+            })),
+            ok(json!({
+                "query": {"wikilambdaload_zobjects": {"Z10001": {"data": {"Z1K1": "Z2"}}}}
+            })),
+        ]);
+        let key = FetchKey::canonical("Z10001".to_string());
+        assert_eq!(
+            _fetch_once(&ActionQuery, &url, key).await,
+            Ok(json!({"Z1K1": "Z2"}))
+        );
+        let paths = paths.lock().unwrap();
+        assert_eq!(paths.len(), 2);
+        // the second page is asked with the parameters given by the first
+        assert!(!paths[0].contains("wikilambdaload_continue"));
+        assert!(paths[1].contains("wikilambdaload_continue=Z10001"));
+    }
+
+    #[actix_web::test]
+    async fn fetch_page_without_data() {
+        // the page of Z10001 exists, but is empty
+        let (url, _paths) = fake_server(vec![ok(json!({
+            "query": {"wikilambdaload_zobjects": {"Z10001": {}}}
+        }))]);
+        let key = FetchKey::canonical("Z10001".to_string());
+        assert_eq!(
+            _fetch_once(&ActionQuery, &url, key).await,
+            Ok(empty_persistent_object("Z10001"))
+        );
+    }
+
+    #[actix_web::test]
+    async fn fetch_rest() {
+        let zobject = json!({"Z1K1": "Z2", "Z2K1": {"Z1K1": "Z6", "Z6K1": "Z10001"}});
+        // the ZObject serialized as a json string, or as is
+        let (url, paths) = fake_server(vec![
+            ok(json!({"Z10001": zobject.to_string()})),
+            ok(json!({"Z10001": zobject})),
+            ok(json!({"Z10002": zobject})),
+        ]);
+        let key = FetchKey::canonical("Z10001".to_string());
+        assert_eq!(
+            _fetch_once(&Rest, &url, key.clone()).await,
+            Ok(zobject.clone())
+        );
+        assert_eq!(_fetch_once(&Rest, &url, key.clone()).await, Ok(zobject));
+        assert!(matches!(
+            _fetch_once(&Rest, &url, key).await,
+            Err(MyError::NotFound(_))
+        ));
+        assert_eq!(
+            paths.lock().unwrap()[0],
+            "/rest.php/wikifunctions/v0/fetch/Z10001"
+        );
+    }
+
+    #[actix_web::test]
+    async fn fetch_truncated_body() {
+        // the connection is closed before the end of the announced body
+        let (url, _paths) = fake_server(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\n{\"query\""
+                .to_string(),
+        ]);
+        let key = FetchKey::canonical("Z10001".to_string());
+        assert!(matches!(
+            _fetch_once(&ActionQuery, &url, key).await,
+            Err(MyError::NetworkError(_))
+        ));
+    }
+
+    #[test]
+    fn timeout_overrides() {
+        let overrides = parse_timeout_overrides("Z4=30, Z10000-Z10999=2.5,Z5,Z6=slow");
+        assert_eq!(overrides.len(), 2);
+        let timeout = |z: &str| timeout_override(&overrides, z);
+        assert_eq!(timeout("Z4"), Some(Duration::from_secs(30)));
+        assert_eq!(timeout("Z10500"), Some(Duration::from_secs_f64(2.5)));
+        assert_eq!(timeout("Z10999"), Some(Duration::from_secs_f64(2.5)));
+        // not overridden, or invalid entries
+        assert_eq!(timeout("Z11000"), None);
+        assert_eq!(timeout("Z5"), None);
+        assert_eq!(timeout("Z6"), None);
+        assert_eq!(timeout("Z4K1"), None);
+    }
+
+    static REFRESHES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    #[actix_web::test]
+    async fn single_refresh_at_expiry() {
+        let key = FetchKey::canonical("Z99999902".to_string());
+        let fetcher: Fetcher = |_key| {
+            REFRESHES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            future::ready(Ok(json!("fresh"))).boxed()
+        };
+        // expired, but still within its grace period
+        let now = Instant::now();
+        FORM_CACHE.lock().unwrap().insert(
+            key.clone(),
+            CacheEntry {
+                value: json!("stale"),
+                expires: now.checked_sub(Duration::from_secs(1)).unwrap(),
+                refreshing: false,
+            },
+        );
+        let results = future::join_all((0..10).map(|_| cached_fetch(key.clone(), fetcher))).await;
+        assert!(results.into_iter().all(|res| res == Ok(json!("stale"))));
+        // the refresh runs in the background
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(REFRESHES.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(cached_fetch(key, fetcher).await, Ok(json!("fresh")));
+    }
+
+    // a function Z10001 "Echo" with a single argument Z10001K1 "input"
+    pub(crate) fn source() -> Source {
+        let label = |text: &str| {
+            json!({
+                "Z1K1": "Z12",
+                "Z12K1": ["Z11", {"Z1K1": "Z11", "Z11K1": "Z1002", "Z11K2": text}]
+            })
+        };
+        Arc::new(Dump::from(HashMap::from([(
+            "Z10001".to_string(),
+            json!({
+                "Z1K1": "Z2",
+                "Z2K1": {"Z1K1": "Z6", "Z6K1": "Z10001"},
+                "Z2K2": {
+                    "Z1K1": "Z8",
+                    "Z8K1": ["Z17", {
+                        "Z1K1": "Z17",
+                        "Z17K1": "Z6",
+                        "Z17K2": "Z10001K1",
+                        "Z17K3": label("input")
+                    }],
+                    "Z8K2": "Z6",
+                },
+                "Z2K3": label("Echo"),
+            }),
+        )])))
+    }
+
+    fn shown(s: StringType) -> String {
+        s.choose_lang(&LabelFormat::from_langs(vec!["Z1002".to_string()]))
+    }
+
+    // a document referencing the same ZID thousands of times builds its label once
+    #[actix_web::test]
+    async fn repeated_zid_memoized() {
+        let fmt = LabelFormat::from_langs(vec!["Z1002".to_string()]);
+        let memo = Memo::new(&fmt, source());
+        let v = _labelize_json(json!(vec!["Z10001"; 1000]), &memo).await;
+        assert_eq!(memo.labels.lock().unwrap().len(), 1);
+        assert_eq!(v.choose_lang(&fmt), json!(vec!["Z10001: Echo"; 1000]));
+    }
+
+    // benchmark, run with: cargo test --release repeated_zid_bench -- --ignored --nocapture
+    #[ignore]
+    #[actix_web::test]
+    async fn repeated_zid_bench() {
+        let fmt = LabelFormat::from_langs(vec!["Z1002".to_string()]);
+        let v = json!(vec![json!({"Z10001K1": "Z10001"}); 10_000]);
+        let runs = 20;
+        let start = Instant::now();
+        for _ in 0..runs {
+            labelize(v.clone(), &fmt, source()).await;
+        }
+        println!(
+            "labelize of 20000 references to Z10001: {:?} per run",
+            start.elapsed() / runs
+        );
+    }
+
+    #[actix_web::test]
+    async fn empty_string() {
+        assert_eq!(
+            labelize_string("".to_string(), source()).await,
+            StringType::String("".to_string())
+        );
+    }
+
+    #[actix_web::test]
+    async fn plain_word() {
+        assert_eq!(
+            labelize_string("hello".to_string(), source()).await,
+            StringType::String("hello".to_string())
+        );
+    }
+
+    #[actix_web::test]
+    async fn zid() {
+        assert_eq!(
+            shown(labelize_string("Z10001".to_string(), source()).await),
+            "Z10001: Echo"
+        );
+    }
+
+    #[actix_web::test]
+    async fn global_key() {
+        assert_eq!(
+            shown(labelize_string("Z10001K1".to_string(), source()).await),
+            "Z10001K1: 'input'"
+        );
+    }
+
+    #[actix_web::test]
+    async fn document() {
+        let fmt = LabelFormat::from_langs(vec!["Z1002".to_string()]);
+        let v = json!({"Z1K1": "Z7", "Z7K1": "Z10001", "Z10001K1": "hello"});
+        // Z7 is not in the dump
+        assert_eq!(
+            labelize(v, &fmt, source()).await.choose_lang(&fmt),
+            json!({
+                "Z1K1: 'type'": "Z7: <unresolved>",
+                "Z7K1: <unresolved>": "Z10001: Echo",
+                "Z10001K1: 'input'": "hello"
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn quoted_reference_kept() {
+        let fmt = LabelFormat::from_langs(vec!["Z1002".to_string()]);
+        let v = json!({"Z1K1": "Z99", "Z99K1": {"Z1K1": "Z9", "Z9K1": "Z10001"}});
+        assert_eq!(
+            labelize(v, &fmt, source()).await.choose_lang(&fmt),
+            json!({
+                "Z1K1: 'type'": "Z99: <unresolved>",
+                "Z99K1: <unresolved>": {"Z1K1": "Z9", "Z9K1": "Z10001"}
+            })
+        );
+        let v = json!({"Z1K1": "Z99", "Z99K1": "Z10001"});
+        assert_eq!(
+            labelize(v, &fmt, source()).await.choose_lang(&fmt),
+            json!({"Z1K1: 'type'": "Z99: <unresolved>", "Z99K1: <unresolved>": "Z10001"})
+        );
+    }
+
+    #[actix_web::test]
+    async fn replace_keeps_quoted() {
+        let fmt = LabelFormat::from_langs(vec!["Z1002".to_string()]);
+        let v = json!({"a": "Z10001", "q": {"Z1K1": "Z99", "Z99K1": ["Z10001"]}});
+        assert_eq!(
+            Value::from(replace_labels(v.into(), &fmt, source()).await),
+            json!({"a": "Z10001: Echo", "q": {"Z1K1": "Z99: <unresolved>", "Z99K1": ["Z10001"]}})
+        );
+    }
+
+    // the labels streamed for a document, as (JSON pointer, is_key, label) sorted by path
+    async fn patches(v: Value, fmt: &LabelFormat) -> Vec<(String, bool, String)> {
+        let mut patches: Vec<_> = labels_as_resolved(&v, fmt, source())
+            .flat_map(|(occurrences, label)| {
+                let label = label.choose_lang(fmt);
+                futures::stream::iter(
+                    occurrences
+                        .into_iter()
+                        .map(move |o| (o.path, o.is_key, label.clone())),
+                )
+            })
+            .collect()
+            .await;
+        patches.sort();
+        patches
+    }
+
+    #[actix_web::test]
+    async fn labels_as_resolved_references() {
+        let fmt = LabelFormat::from_langs(vec!["Z1002".to_string()]);
+        let v = json!({"Z7K1": "Z10001", "Z10001K1": {"Z1K1": "Z99", "Z99K1": "Z10001"}});
+        // the quoted Z10001 is not labelled
+        let patch = |path: &str, is_key, label: &str| (path.to_string(), is_key, label.to_string());
+        assert_eq!(
+            patches(v.clone(), &fmt).await,
+            vec![
+                patch("/Z10001K1", true, "Z10001K1: 'input'"),
+                patch("/Z10001K1/Z1K1", false, "Z99: <unresolved>"),
+                patch("/Z10001K1/Z1K1", true, "Z1K1: 'type'"),
+                patch("/Z10001K1/Z99K1", true, "Z99K1: <unresolved>"),
+                patch("/Z7K1", false, "Z10001: Echo"),
+                patch("/Z7K1", true, "Z7K1: <unresolved>"),
+            ]
+        );
+        // with "labelize": false, the strings are streamed as is
+        let fmt = LabelFormat {
+            no_labels: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            patches(v, &fmt).await,
+            vec![
+                patch("/Z10001K1", true, "Z10001K1"),
+                patch("/Z10001K1/Z1K1", false, "Z99"),
+                patch("/Z10001K1/Z1K1", true, "Z1K1"),
+                patch("/Z10001K1/Z99K1", true, "Z99K1"),
+                patch("/Z7K1", false, "Z10001"),
+                patch("/Z7K1", true, "Z7K1"),
+            ]
+        );
     }
 }
@@ -0,0 +1,29 @@
+// The language ZIDs of the most common language codes (BCP-47), e.g. "en" for English (Z1002)
+const LANGUAGE_ZIDS: [(&str, &str); 8] = [
+    ("ar", "Z1001"),
+    ("en", "Z1002"),
+    ("es", "Z1003"),
+    ("fr", "Z1004"),
+    ("ru", "Z1005"),
+    ("zh", "Z1006"),
+    ("de", "Z1430"),
+    ("ja", "Z1830"),
+];
+
+// the language ZID of a language code, by its primary language subtag, e.g. "zh-Hans" as "zh"
+pub fn lang_zid(code: &str) -> Option<&'static str> {
+    let primary = code.trim().split(['-', '_']).next()?;
+    LANGUAGE_ZIDS
+        .iter()
+        .find(|(c, _zid)| c.eq_ignore_ascii_case(primary))
+        .map(|(_c, zid)| *zid)
+}
+
+// a language given by the user, as a language ZID if it is a known language code,
+// as is otherwise, e.g. if it is a ZID already
+pub fn to_lang_zid(lang: String) -> String {
+    match lang_zid(&lang) {
+        Some(zid) => zid.to_string(),
+        None => lang,
+    }
+}
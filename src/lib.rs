@@ -0,0 +1,102 @@
+// Making Wikifunctions ZObjects human readable, without the http server:
+// labelize() fetches the labels of the ZIDs of a ZObject, giving a SimpleValue,
+// which is then converted to TypedForm, IntermediateForm (compressed), and CompactValue,
+// each of them turned back into json with choose_lang()
+
+pub mod compact_key;
+pub mod compact_value;
+pub mod expand;
+pub mod intermediate_form;
+pub mod labelize;
+pub mod language_codes;
+pub mod lru;
+pub mod ordered_value;
+pub mod simple_value;
+pub mod typed_form;
+
+pub use compact_value::CompactValue;
+pub use intermediate_form::IntermediateForm;
+pub use labelize::{labelize, Dump, FileSource, LabelSource, Source, Wikifunctions};
+pub use ordered_value::OrderedValue;
+pub use simple_value::{LabelFormat, SimpleValue, StringType};
+pub use typed_form::TypedForm;
+
+pub const DOMAIN: &str = "https://wikifunctions.org/w";
+
+// the compression passes of compactify(), in the order they are run
+pub const PASSES: [&str; 8] = [
+    "reference",
+    "string",
+    "monolingual",
+    "function",
+    "list",
+    "array_item_types",
+    "simple_classes",
+    "key_types",
+];
+
+// the IntermediateForm after all the compress passes, right before the conversion to CompactValue
+// with mark_dropped, array items keep a marker where their type was dropped
+pub fn compressed(val: SimpleValue, fmt: &LabelFormat, mark_dropped: bool) -> IntermediateForm {
+    let mut val = IntermediateForm::from(TypedForm::from_simple(val, !fmt.untyped_arrays));
+    if fmt.runs_pass("reference") {
+        val = val.compress_reference();
+    }
+    if fmt.runs_pass("string") {
+        val = val.compress_string();
+    }
+    if fmt.runs_pass("monolingual") {
+        val = val.compress_monolingual();
+    }
+    if fmt.runs_pass("function") {
+        val = val.compress_function();
+    }
+    if fmt.runs_pass("list") {
+        val = val.compress_list();
+    }
+    if fmt.runs_pass("array_item_types") {
+        val = val.drop_array_item_types(mark_dropped);
+    }
+    val
+}
+
+pub fn compactify(val: SimpleValue, fmt: &LabelFormat, mark_dropped: bool) -> CompactValue {
+    let mut val: CompactValue = compressed(val, fmt, mark_dropped).into();
+    if fmt.runs_pass("simple_classes") {
+        val = val.compress_simple_classes();
+    }
+    if fmt.runs_pass("key_types") {
+        val = val.dedup_key_types();
+    }
+    val
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn monolingual_pass_alone() {
+        // without the reference and string passes, Z11K1 and Z11K2 are still objects,
+        // the monolingual text is left uncompressed
+        let fmt = LabelFormat {
+            no_labels: true,
+            passes: Some(["monolingual".to_string()].into()),
+            ..Default::default()
+        };
+        let v = json!({
+            "Z1K1": "Z11",
+            "Z11K1": {"Z1K1": "Z9", "Z9K1": "Z1002"},
+            "Z11K2": {"Z1K1": "Z6", "Z6K1": "hello"}
+        });
+        let val = labelize(v, &fmt, Arc::new(Wikifunctions)).await;
+        assert_eq!(
+            compactify(val, &fmt, false).choose_lang(&fmt),
+            json!({"[Z11]": {"Z11K1 [Z9]": {"Z9K1": "Z1002"}, "Z11K2 [Z6]": {"Z6K1": "hello"}}})
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// A map remembering the order in which its entries were last used (inserted or read with get_mut),
+// so that the least recently used one is found in O(log n), to be evicted when the map is full
+pub struct LruMap<K, V> {
+    entries: HashMap<K, (V, u64)>,
+    // the keys by their last use, the least recent first
+    order: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Default for LruMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> LruMap<K, V> {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // the value, without counting it as used
+    pub fn get<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        self.entries.get(key).map(|(value, _used)| value)
+    }
+
+    pub fn contains_key<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.entries.contains_key(key)
+    }
+
+    // the value, now the most recently used
+    pub fn get_mut<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+    {
+        let now = self.tick();
+        let (value, used) = self.entries.get_mut(key)?;
+        let k = self.order.remove(used)?;
+        self.order.insert(now, k);
+        *used = now;
+        Some(value)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        let now = self.tick();
+        if let Some((_value, used)) = self.entries.insert(key.clone(), (value, now)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(now, key);
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &mut V) -> bool) {
+        let order = &mut self.order;
+        self.entries.retain(|key, (value, used)| {
+            let kept = keep(key, value);
+            if !kept {
+                order.remove(used);
+            }
+            kept
+        });
+    }
+
+    // remove the least recently used entry
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        let (_used, key) = self.order.pop_first()?;
+        let (value, _used) = self.entries.remove(&key)?;
+        Some((key, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_first() {
+        let mut map = LruMap::default();
+        map.insert("a", 1);
+        map.insert("b", 2);
+        map.insert("c", 3);
+        // a is used again, b inserted again: c is now the least recently used
+        *map.get_mut(&"a").unwrap() += 10;
+        map.insert("b", 20);
+        // get() doesn't count as a use
+        assert_eq!(map.get(&"c"), Some(&3));
+        assert_eq!(map.pop_lru(), Some(("c", 3)));
+        assert_eq!(map.pop_lru(), Some(("a", 11)));
+        assert_eq!(map.pop_lru(), Some(("b", 20)));
+        assert_eq!(map.pop_lru(), None);
+    }
+
+    #[test]
+    fn retain_keeps_the_order() {
+        let mut map = LruMap::default();
+        for (i, key) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            map.insert(key, i);
+        }
+        map.retain(|_key, i| *i % 2 == 1);
+        assert_eq!(map.len(), 2);
+        assert!(!map.contains_key(&"a"));
+        assert_eq!(map.pop_lru(), Some(("b", 1)));
+        assert_eq!(map.pop_lru(), Some(("d", 3)));
+        assert!(map.is_empty());
+    }
+}
@@ -1,17 +1,26 @@
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use serde_json::Value;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
 
-use actix_web::dev::Service;
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::cookie::Cookie;
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorInternalServerError;
 use actix_web::http::header;
-use actix_web::{route, App, HttpResponse, HttpServer, Responder};
-use tracing::{debug, info};
+use actix_web::{
+    route, web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder,
+};
+use tracing::{debug, info, warn};
 use tracing_actix_web::TracingLogger;
 
 use dotenv::dotenv;
 
-const DOMAIN: &str = "https://wikifunctions.org/w";
-
 #[route("/", method = "GET")]
 async fn index() -> impl Responder {
     info!("get index page");
@@ -20,6 +29,22 @@ async fn index() -> impl Responder {
         .body(include_str!("../static/index.html"))
 }
 
+#[route("/health", method = "GET")]
+async fn health() -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "status": "ok",
+        "requests_in_flight": REQUESTS.in_flight(),
+        "requests_total": REQUESTS.total(),
+    }))
+}
+
+#[route("/metrics", method = "GET")]
+async fn metrics_route() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(REQUESTS.render())
+}
+
 #[route("/editor", method = "GET")]
 async fn editor() -> impl Responder {
     info!("get editor page");
@@ -28,169 +53,1169 @@ async fn editor() -> impl Responder {
         .body(include_str!("../static/editor.html"))
 }
 
-mod simple_value;
-mod typed_form;
-use typed_form::TypedForm;
-mod intermediate_form;
-use intermediate_form::IntermediateForm;
-mod compact_key;
-mod compact_value;
-use compact_value::CompactValue;
+mod metrics;
+use metrics::REQUESTS;
+mod hooks;
+mod html;
 
-mod labelize;
-use labelize::labelize;
+use wikifunc_labelize::expand;
+use wikifunc_labelize::intermediate_form::IntermediateForm;
+use wikifunc_labelize::labelize::{
+    fetch_signature, labelize, labelize_single, labels_as_resolved, replace_labels, too_deep,
+    unexpected_scalars, Dump, FileSource, LabelSource, Wikifunctions,
+};
+use wikifunc_labelize::language_codes::{lang_zid, to_lang_zid};
+use wikifunc_labelize::ordered_value::OrderedValue;
+use wikifunc_labelize::simple_value::{KeyCollision, LabelFormat, StringType};
+use wikifunc_labelize::typed_form::TypedForm;
+use wikifunc_labelize::{compactify, compressed, PASSES};
 
 // default to english only
 const DEFAULT_LANGS: [&str; 1] = ["Z1002"];
 
-fn request_wrapper(req_body: String) -> Result<(Value, Vec<String>), HttpResponse> {
-    debug!("parsing req body");
-    let v: Value = match serde_json::from_str(&req_body) {
-        Ok(v) => v,
-        Err(_) => {
+// Options of a language given as an object in "langs", e.g. {"zid": "Z1004", "required": true}
+// - "zid": the language ZID, a string
+// - "required": the language is required, like with "required_lang"
+// - "fallback": the language is the fallback, like with "fallback_lang", and not a prefered language
+// other keys are ignored. The first language marked required / fallback is used
+#[derive(Default)]
+struct LangOptions {
+    required_lang: Option<String>,
+    fallback_lang: Option<String>,
+}
+
+// the ZID of an entry of the array of "langs", None if the language is only the fallback
+#[allow(clippy::result_large_err)]
+fn parse_lang_entry(x: &Value, options: &mut LangOptions) -> Result<Option<String>, HttpResponse> {
+    match x {
+        Value::String(s) => Ok(Some(to_lang_zid(s.clone()))),
+        Value::Object(spec) => {
+            let zid = match spec.get("zid") {
+                Some(Value::String(zid)) => to_lang_zid(zid.clone()),
+                _ => {
+                    return Err(HttpResponse::BadRequest()
+                        .reason("objects in langs should have a string zid")
+                        .finish())
+                }
+            };
+            let flag = |option: &str| match spec.get(option) {
+                None => Ok(false),
+                Some(Value::Bool(b)) => Ok(*b),
+                Some(_) => Err(HttpResponse::BadRequest()
+                    .reason("options of objects in langs should be booleans")
+                    .finish()),
+            };
+            if flag("required")? {
+                options.required_lang.get_or_insert(zid.clone());
+            }
+            if flag("fallback")? {
+                options.fallback_lang.get_or_insert(zid);
+                return Ok(None);
+            }
+            Ok(Some(zid))
+        }
+        _ => Err(HttpResponse::BadRequest()
+            .reason("value of langs should be an array of string or objects")
+            .finish()),
+    }
+}
+
+// The value of "langs" could've been any kind of Value, we have to make sure it is either
+// - an array of string, the language ZIDs (or language codes, e.g. "en") in order of preference,
+//   or objects {"zid": ..., ...} with options for the language (see LangOptions), or
+// - an object of {language ZID: weight}, e.g. {"Z1004": 1.0, "Z1002": 0.8},
+//   ordered by descending weight, similar to the q-values of Accept-Language.
+//   Languages with weight 0 are not acceptable, and dropped
+#[allow(clippy::result_large_err)]
+fn parse_langs(langs: &Value) -> Result<(Vec<String>, LangOptions), HttpResponse> {
+    match langs {
+        Value::Array(langs) => {
+            let mut options = LangOptions::default();
+            let mut zids = Vec::new();
+            for x in langs {
+                zids.extend(parse_lang_entry(x, &mut options)?);
+            }
+            Ok((zids, options))
+        }
+        Value::Object(weights) => {
+            let mut weights = weights
+                .iter()
+                .map(|(lang, weight)| match weight.as_f64() {
+                    Some(weight) => Ok((to_lang_zid(lang.clone()), weight)),
+                    None => Err(HttpResponse::BadRequest()
+                        .reason("weights in langs should be numbers")
+                        .finish()),
+                })
+                .collect::<Result<Vec<(String, f64)>, _>>()?;
+            weights.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+            Ok((
+                weights
+                    .into_iter()
+                    .filter(|(_, weight)| *weight > 0.0)
+                    .map(|(lang, _)| lang)
+                    .collect(),
+                LangOptions::default(),
+            ))
+        }
+        _ => Err(HttpResponse::BadRequest()
+            .reason("value of langs should be an array of string, or an object of weights")
+            .finish()),
+    }
+}
+
+// Sticky language preferences for browsers: ?set_langs=Z1004,Z1002 stores the langs in a cookie,
+// used by later requests that don't give "langs" in the body
+const LANGS_COOKIE: &str = "langs";
+
+fn split_langs(langs: &str) -> Vec<String> {
+    langs
+        .split(',')
+        .map(str::trim)
+        .filter(|lang| !lang.is_empty())
+        .map(|lang| to_lang_zid(lang.to_string()))
+        .collect()
+}
+
+fn query_set_langs(query_string: &str) -> Option<String> {
+    web::Query::<HashMap<String, String>>::from_query(query_string)
+        .ok()?
+        .get("set_langs")
+        .cloned()
+}
+
+// json responses are pretty-printed with ?pretty=true (or ?pretty=1),
+// or when the client accepts "application/json; pretty"
+fn query_pretty(req: &ServiceRequest) -> bool {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string());
+    let in_query = query
+        .ok()
+        .and_then(|query| query.get("pretty").cloned())
+        .is_some_and(|pretty| pretty == "true" || pretty == "1");
+    in_query
+        || req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| {
+                accept.split(',').any(|media| {
+                    let mut params = media.split(';').map(str::trim);
+                    params.next() == Some("application/json") && params.any(|p| p == "pretty")
+                })
+            })
+}
+
+// the langs of the Accept-Language header, e.g. "fr-CH, fr;q=0.9, en;q=0.8",
+// ordered by descending q-value, only by their primary language code, the unknown codes being dropped
+fn accept_language(req: &HttpRequest) -> Option<Vec<String>> {
+    let header = req.headers().get(header::ACCEPT_LANGUAGE)?.to_str().ok()?;
+    let mut weighted: Vec<(&str, f64)> = header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let code = parts.next()?;
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse().ok())?;
+            Some((lang_zid(code)?, q))
+        })
+        .filter(|(_zid, q)| *q > 0.0)
+        .collect();
+    weighted.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    let mut langs: Vec<String> = Vec::new();
+    for (zid, _q) in weighted {
+        if !langs.iter().any(|l| l == zid) {
+            langs.push(zid.to_string());
+        }
+    }
+    Some(langs)
+}
+
+// the langs used when the body doesn't give any:
+// ?set_langs, then the langs cookie, then the Accept-Language header, then the default
+fn sticky_langs(req: &HttpRequest) -> Vec<String> {
+    query_set_langs(req.query_string())
+        .map(|langs| split_langs(&langs))
+        .or_else(|| req.cookie(LANGS_COOKIE).map(|c| split_langs(c.value())))
+        .filter(|langs| !langs.is_empty())
+        .or_else(|| accept_language(req))
+        .filter(|langs| !langs.is_empty())
+        .unwrap_or_else(|| DEFAULT_LANGS.into_iter().map(|s| s.to_string()).collect())
+}
+
+// besides "data", the keys in the request body that configure the request
+const OPTION_KEYS: [&str; 17] = [
+    "labelize",
+    "preserve_original",
+    "langs",
+    "required_lang",
+    "fallback_lang",
+    "mark_fallback",
+    "deterministic",
+    "key_quote",
+    "links",
+    "labels",
+    "omit_matching_lang",
+    "resolve_aliases",
+    "type_formats",
+    "label_metadata",
+    "on_collision",
+    "assume_benjamin",
+    "passes",
+];
+
+#[allow(clippy::result_large_err)]
+fn bool_option(
+    obj: &serde_json::Map<String, Value>,
+    key: &str,
+    reason: &'static str,
+) -> Result<bool, HttpResponse> {
+    match obj.get(key) {
+        None => Ok(false),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(_) => Err(HttpResponse::BadRequest().reason(reason).finish()),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn string_option(
+    obj: &serde_json::Map<String, Value>,
+    key: &str,
+    reason: &'static str,
+) -> Result<Option<String>, HttpResponse> {
+    match obj.get(key) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(HttpResponse::BadRequest().reason(reason).finish()),
+    }
+}
+
+// the options of the request, from the option keys of the request body
+#[allow(clippy::result_large_err)]
+fn parse_options(
+    obj: &serde_json::Map<String, Value>,
+    req: &HttpRequest,
+) -> Result<LabelFormat, HttpResponse> {
+    let (langs, lang_options) = match obj.get("langs") {
+        None => (sticky_langs(req), LangOptions::default()),
+        Some(langs) => parse_langs(langs)?,
+    };
+    let required_lang = string_option(
+        obj,
+        "required_lang",
+        "value of required_lang should be a string",
+    )?
+    .map(to_lang_zid)
+    .or(lang_options.required_lang);
+    let fallback_lang = string_option(
+        obj,
+        "fallback_lang",
+        "value of fallback_lang should be a string",
+    )?
+    .map(to_lang_zid)
+    .or(lang_options.fallback_lang);
+    // no language at all (e.g. "langs": [], or only weights 0) is most likely a mistake,
+    // rather than a request for whatever label comes first
+    if langs.is_empty() && fallback_lang.is_none() {
+        return Err(HttpResponse::BadRequest()
+            .reason("langs should have at least one language")
+            .finish());
+    }
+    let mark_fallback = bool_option(
+        obj,
+        "mark_fallback",
+        "value of mark_fallback should be a boolean",
+    )?;
+    let deterministic = bool_option(
+        obj,
+        "deterministic",
+        "value of deterministic should be a boolean",
+    )?;
+    let key_quote = string_option(obj, "key_quote", "value of key_quote should be a string")?;
+    let preserve_original = bool_option(
+        obj,
+        "preserve_original",
+        "value of preserve_original should be a boolean",
+    )?;
+    let links = bool_option(obj, "links", "value of links should be a boolean")?;
+    // labels are displayed with their ZID, unless "labels": "only"
+    let labels_only = match obj.get("labels").map(|v| v.as_str()) {
+        None | Some(Some("full")) => false,
+        Some(Some("only")) => true,
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .reason("value of labels should be one of full, only")
+                .finish())
+        }
+    };
+    let omit_matching_lang = bool_option(
+        obj,
+        "omit_matching_lang",
+        "value of omit_matching_lang should be a boolean",
+    )?;
+    let resolve_aliases = bool_option(
+        obj,
+        "resolve_aliases",
+        "value of resolve_aliases should be a boolean",
+    )?;
+    let label_metadata = bool_option(
+        obj,
+        "label_metadata",
+        "value of label_metadata should be a boolean",
+    )?;
+    let on_collision =
+        match obj.get("on_collision").map(|v| v.as_str()) {
+            None => KeyCollision::default(),
+            Some(Some("keep_last")) => KeyCollision::KeepLast,
+            Some(Some("keep_first")) => KeyCollision::KeepFirst,
+            Some(Some("merge")) => KeyCollision::Merge,
+            Some(Some("error")) => KeyCollision::Error,
+            Some(_) => return Err(HttpResponse::BadRequest()
+                .reason(
+                    "value of on_collision should be one of keep_last, keep_first, merge, error",
+                )
+                .finish()),
+        };
+    // {type ZID: template}, e.g. {"Z8": "{}()", "Z4": "«{}»"}
+    let type_formats = match obj.get("type_formats") {
+        None => BTreeMap::new(),
+        Some(Value::Object(formats)) => formats
+            .iter()
+            .map(|(zid, template)| match template {
+                Value::String(template) => Ok((zid.clone(), template.clone())),
+                _ => Err(HttpResponse::BadRequest()
+                    .reason("templates in type_formats should be strings")
+                    .finish()),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .reason("value of type_formats should be an object")
+                .finish())
+        }
+    };
+    // ZIDs are labelled unless "labelize": false
+    let no_labels = match obj.get("labelize") {
+        None => false,
+        Some(Value::Bool(b)) => !b,
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .reason("value of labelize should be a boolean")
+                .finish())
+        }
+    };
+    // arrays are assumed to be "Benjamin arrays" unless "assume_benjamin": false
+    let untyped_arrays = match obj.get("assume_benjamin") {
+        None => false,
+        Some(Value::Bool(b)) => !b,
+        Some(_) => {
             return Err(HttpResponse::BadRequest()
-                .reason("invalid json object")
+                .reason("value of assume_benjamin should be a boolean")
                 .finish())
         }
     };
-    match v {
-        Value::Object(obj) => {
-            // if the request body has both key "data" and key "langs",
-            // we use the custom supplied langs when calling choose_lang()
-            if obj.contains_key("data") && obj.contains_key("langs") {
-                // but the value of "langs" could've been any kind of Value
-                // we have to make sure it is a Vec<String>
-                match obj.get("langs").unwrap() {
-                    Value::Array(langs) => {
-                        let langs: Vec<String> = langs
-                            .iter()
-                            .map(|x| match x {
-                                Value::String(s) => Ok(s.clone()),
-                                _ => Err(HttpResponse::BadRequest()
-                                    .reason("value of langs should be an array of string")
-                                    .finish()),
-                            })
-                            .collect::<Result<Vec<String>, _>>()?;
-
-                        // TODO: can we not clone the data?
-                        Ok((obj.get("data").unwrap().clone(), langs))
-                    }
+    // only the compression passes listed are run, in their usual order, e.g. ["reference", "string"]
+    let passes = match obj.get("passes") {
+        None => None,
+        Some(Value::Array(passes)) => Some(
+            passes
+                .iter()
+                .map(|pass| match pass.as_str() {
+                    Some(pass) if PASSES.contains(&pass) => Ok(pass.to_string()),
                     _ => Err(HttpResponse::BadRequest()
-                        .reason("value of langs should be an array of string")
+                        .reason("unknown compression pass in passes")
                         .finish()),
-                }
-            } else {
-                Ok((
-                    Value::Object(obj),
-                    DEFAULT_LANGS.into_iter().map(|s| s.to_string()).collect(),
-                ))
-            }
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .reason("value of passes should be an array")
+                .finish())
+        }
+    };
+    Ok(LabelFormat {
+        langs,
+        required_lang,
+        fallback_lang,
+        mark_fallback,
+        deterministic,
+        key_quote,
+        preserve_original,
+        links,
+        labels_only,
+        omit_matching_lang,
+        resolve_aliases,
+        label_metadata,
+        on_collision,
+        type_formats,
+        no_labels,
+        untyped_arrays,
+        passes,
+        ..Default::default()
+    })
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_body(req_body: &str) -> Result<Value, HttpResponse> {
+    debug!("parsing req body");
+    let val = serde_json::from_str(req_body).map_err(|_| {
+        HttpResponse::BadRequest()
+            .reason("invalid json object")
+            .finish()
+    })?;
+    check_depth(val)
+}
+
+#[allow(clippy::result_large_err)]
+fn check_depth(val: Value) -> Result<Value, HttpResponse> {
+    if too_deep(&val) {
+        return Err(HttpResponse::BadRequest()
+            .reason("the document is nested too deeply")
+            .finish());
+    }
+    Ok(val)
+}
+
+// GET requests can give the payload in the query instead of the body, as ?data=...&langs=...,
+// e.g. /labelize?data=Z801&langs=Z1002 for links to share.
+// data is parsed as json, or taken as a string if it isn't json, and langs are comma separated
+#[allow(clippy::result_large_err)]
+fn request_wrapper(
+    req: &HttpRequest,
+    req_body: String,
+) -> Result<(Value, LabelFormat), HttpResponse> {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map(web::Query::into_inner)
+        .unwrap_or_default();
+    let query_data = query.get("data").filter(|_| req_body.trim().is_empty());
+    let payload = match query_data {
+        Some(data) => {
+            check_depth(serde_json::from_str(data).unwrap_or_else(|_| Value::String(data.clone())))?
+        }
+        None => parse_body(&req_body)?,
+    };
+    let (val, mut fmt) = match payload {
+        // if the request body has key "data" and any of the option keys (e.g. "langs"),
+        // we use the custom supplied options when calling choose_lang()
+        Value::Object(mut obj)
+            if obj.contains_key("data") && OPTION_KEYS.iter().any(|k| obj.contains_key(*k)) =>
+        {
+            let fmt = parse_options(&obj, req)?;
+            (obj.remove("data").unwrap(), fmt)
+        }
+        v => (v, LabelFormat::from_langs(sticky_langs(req))),
+    };
+    if let Some(langs) = query.get("langs").filter(|_| query_data.is_some()) {
+        let langs = split_langs(langs);
+        if !langs.is_empty() {
+            fmt.langs = langs;
+        }
+    }
+    // ?labels=only, the same as "labels": "only" in the body
+    if query.get("labels").map(String::as_str) == Some("only") {
+        fmt.labels_only = true;
+    }
+    Ok((val, fmt))
+}
+
+// boolean options in the query string are enabled with ?option=true
+fn query_flag(query: &HashMap<String, String>, option: &str) -> bool {
+    query.get(option).map(String::as_str) == Some("true")
+}
+
+// unix timestamp of when the server, and so its in-memory fetch cache, was started
+static CACHE_EPOCH: Lazy<u64> = Lazy::new(|| {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+});
+
+// With ?diagnostics=true, the document is checked for numbers, booleans, and nulls,
+// which can't be labelized, and are reported as warnings {"path": JSON pointer, "type": ...}
+fn diagnose(query: &HashMap<String, String>, val: &Value) -> Option<HttpResponse> {
+    if !query_flag(query, "diagnostics") {
+        return None;
+    }
+    let warnings = unexpected_scalars(val);
+    if warnings.is_empty() {
+        return None;
+    }
+    Some(
+        HttpResponse::BadRequest()
+            .reason("unexpected json scalars in the document")
+            .json(json!({ "warnings": warnings })),
+    )
+}
+
+static POST_PROCESS: Lazy<Option<hooks::PostProcess>> = Lazy::new(hooks::post_process);
+
+// The final json of a response, whatever its format, and the response with its headers:
+// with ?envelope=true, the result is wrapped along with the resolved langs and other metadata
+// the result keeps the order of its keys if it has one (see /replace), but for the hooks,
+// which get and return plain json
+#[allow(clippy::result_large_err)]
+fn finish(
+    query: &HashMap<String, String>,
+    fmt: &LabelFormat,
+    result: impl Into<OrderedValue>,
+) -> Result<(HttpResponseBuilder, OrderedValue), HttpResponse> {
+    if fmt.on_collision == KeyCollision::Error && !fmt.collisions.lock().unwrap().is_empty() {
+        return Err(HttpResponse::BadRequest()
+            .reason("different keys of an object have the same label")
+            .json(json!({ "collisions": *fmt.collisions.lock().unwrap() })));
+    }
+    // a result with ZIDs left unlabelled is marked as incomplete, with the header
+    // X-Labelize-Incomplete: reason, and in the envelope
+    let incomplete = *fmt.incomplete.lock().unwrap();
+    let mut res = HttpResponse::Ok();
+    if let Some(reason) = incomplete {
+        res.insert_header(("X-Labelize-Incomplete", reason.as_str()));
+    }
+    let body = if query_flag(query, "envelope") {
+        let mut envelope = vec![
+            ("langs".to_string(), json!(fmt.langs).into()),
+            ("cache_epoch".to_string(), json!(*CACHE_EPOCH).into()),
+            ("result".to_string(), result.into()),
+        ];
+        if let Some(required_lang) = &fmt.required_lang {
+            envelope.push(("required_lang".to_string(), json!(required_lang).into()));
+            envelope.push((
+                "missing_required_lang".to_string(),
+                json!(*fmt.missing_required.lock().unwrap()).into(),
+            ));
         }
-        _ => Ok((
-            v,
-            DEFAULT_LANGS.into_iter().map(|s| s.to_string()).collect(),
-        )),
+        if let Some(reason) = incomplete {
+            envelope.push(("incomplete".to_string(), json!(true).into()));
+            envelope.push(("reason".to_string(), json!(reason.as_str()).into()));
+        }
+        OrderedValue::Object(envelope)
+    } else {
+        result.into()
+    };
+    match POST_PROCESS.as_ref() {
+        Some(hook) => Ok((res, hook(body.into(), &fmt.langs).into())),
+        None => Ok((res, body)),
     }
 }
 
+fn respond(
+    query: &HashMap<String, String>,
+    fmt: &LabelFormat,
+    result: impl Into<OrderedValue>,
+) -> HttpResponse {
+    match finish(query, fmt, result) {
+        Ok((mut res, body)) => res.json(body),
+        Err(r) => r,
+    }
+}
+
+// with ?format=html, or when the client asks for text/html and nothing else, the result is rendered
+// as an html page (a browser's Accept, listing other types too, still gets json)
+fn query_html(query: &HashMap<String, String>, req: &HttpRequest) -> bool {
+    query.get("format").map(String::as_str) == Some("html")
+        || req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(|accept| accept.split(';').next())
+            .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("text/html"))
+}
+
+fn respond_html(
+    query: &HashMap<String, String>,
+    fmt: &LabelFormat,
+    result: impl Into<OrderedValue>,
+) -> HttpResponse {
+    match finish(query, fmt, result) {
+        Ok((mut res, body)) => res
+            .append_header(header::ContentType::html())
+            .body(html::render_page(&body.into())),
+        Err(r) => r,
+    }
+}
+
+// with ?format=ndjson, a top-level array is streamed as newline delimited json,
+// each element serialized one at a time
+// (the elements are converted beforehand, so that the collisions are known before the response starts)
+fn query_ndjson(query: &HashMap<String, String>) -> bool {
+    query.get("format").map(String::as_str) == Some("ndjson")
+}
+
+fn respond_ndjson(
+    query: &HashMap<String, String>,
+    fmt: &LabelFormat,
+    result: impl Into<OrderedValue>,
+) -> HttpResponse {
+    let (mut res, body) = match finish(query, fmt, result) {
+        Ok((res, body)) => (res, body),
+        Err(r) => return r,
+    };
+    let lines = match body {
+        OrderedValue::Array(v) => v,
+        body => vec![body],
+    };
+    res.content_type("application/x-ndjson")
+        .streaming(futures::stream::iter(lines.into_iter().map(|x| {
+            serde_json::to_string(&x)
+                .map(|line| web::Bytes::from(line + "\n"))
+                .map_err(ErrorInternalServerError)
+        })))
+}
+
+// With ?format=patches, the labels are streamed as server-sent events, as soon as they resolve:
+// first a "skeleton" event with the document as is, then a "label" event for each labelled string,
+// {"path": JSON pointer, "key": bool, "label": ...}, where key is true when the label replaces
+// the key of the object entry at path instead of its value, and last a "done" event
+fn respond_patches(
+    val: Value,
+    fmt: LabelFormat,
+    source: web::Data<dyn LabelSource>,
+) -> HttpResponse {
+    let event = |name: &str, data: Value| {
+        Ok::<_, actix_web::Error>(web::Bytes::from(format!(
+            "event: {}\ndata: {}\n\n",
+            name, data
+        )))
+    };
+    let skeleton = futures::stream::once(futures::future::ready(event("skeleton", val.clone())));
+    let labels = labels_as_resolved(&val, &fmt, source.into_inner())
+        .filter(|(_occurrences, label)| {
+            futures::future::ready(matches!(label, StringType::LabelledNode(_)))
+        })
+        .flat_map(move |(occurrences, label)| {
+            let label = label.choose_lang(&fmt);
+            futures::stream::iter(occurrences.into_iter().map(move |o| {
+                event(
+                    "label",
+                    json!({"path": o.path, "key": o.is_key, "label": label}),
+                )
+            }))
+        });
+    let done = futures::stream::once(futures::future::ready(event("done", json!({}))));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(skeleton.chain(labels).chain(done))
+}
+
 #[route("/labelize", method = "GET", method = "POST")]
-async fn labelize_route(req_body: String) -> impl Responder {
+async fn labelize_route(
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    source: web::Data<dyn LabelSource>,
+    req_body: String,
+) -> impl Responder {
     info!("labelize route");
-    let (val, langs) = match request_wrapper(req_body) {
-        Ok((val, langs)) => (val, langs),
+    let _in_flight = REQUESTS.start();
+    let (val, fmt) = match request_wrapper(&req, req_body) {
+        Ok((val, fmt)) => (val, fmt),
         Err(r) => return r,
     };
-    let val = labelize(val).await;
-    HttpResponse::Ok().json(val.choose_lang(&langs))
+    if let Some(r) = diagnose(&query, &val) {
+        return r;
+    }
+    if query.get("format").map(String::as_str) == Some("patches") {
+        return respond_patches(val, fmt, source);
+    }
+    let mut val = labelize(val, &fmt, source.into_inner()).await;
+    // ?raw_array_types=true keeps the type header of arrays as the raw ZID
+    if query_flag(&query, "raw_array_types") {
+        val = val.raw_array_types();
+    }
+    // ?glossary_only=true returns only the sorted, deduplicated labels found in the document
+    if query_flag(&query, "glossary_only") {
+        return respond(&query, &fmt, json!(val.glossary(&fmt)));
+    }
+    // ?label_dictionary=true returns the labels once each, {"labels": {ZID: label}, "data": ...},
+    // the data keeping the ZIDs as is
+    if query_flag(&query, "label_dictionary") {
+        let labels = val.label_dictionary(&fmt);
+        let data = val.into_raw().choose_lang(&fmt);
+        return respond(&query, &fmt, json!({ "labels": labels, "data": data }));
+    }
+    if query_ndjson(&query) {
+        return respond_ndjson(&query, &fmt, val.choose_lang(&fmt));
+    }
+    respond(&query, &fmt, val.choose_lang(&fmt))
 }
 
+// at most this many documents of a batch are labelized at the same time,
+// and batches of more than MAX_BATCH_DOCUMENTS are rejected,
+// configured with env BATCH_CONCURRENCY (default 8) and MAX_BATCH_DOCUMENTS (default 100)
+static BATCH_CONCURRENCY: Lazy<usize> = Lazy::new(|| {
+    std::env::var("BATCH_CONCURRENCY")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(8)
+});
+static MAX_BATCH_DOCUMENTS: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MAX_BATCH_DOCUMENTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(100)
+});
+
+// {"documents": [...], "langs": ...}: each document is labelized as by /labelize,
+// all with the same options, and the results returned in the same order
+#[route("/labelize/batch", method = "POST")]
+async fn labelize_batch_route(
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    source: web::Data<dyn LabelSource>,
+    req_body: String,
+) -> impl Responder {
+    info!("labelize batch route");
+    let _in_flight = REQUESTS.start();
+    let (documents, fmt) = match parse_body(&req_body) {
+        Ok(Value::Object(mut obj)) => match (obj.remove("documents"), parse_options(&obj, &req)) {
+            (Some(Value::Array(documents)), Ok(fmt)) => (documents, fmt),
+            (_, Err(r)) => return r,
+            (_, Ok(_)) => {
+                return HttpResponse::BadRequest()
+                    .reason("value of documents should be an array")
+                    .finish()
+            }
+        },
+        Ok(_) => {
+            return HttpResponse::BadRequest()
+                .reason("the request should be an object with key documents")
+                .finish()
+        }
+        Err(r) => return r,
+    };
+    if documents.len() > *MAX_BATCH_DOCUMENTS {
+        return HttpResponse::BadRequest()
+            .reason("too many documents in the batch")
+            .finish();
+    }
+    let fmt = &fmt;
+    let results: Vec<Value> = futures::stream::iter(documents.into_iter().map(|doc| {
+        let source = source.clone().into_inner();
+        async move { labelize(doc, fmt, source).await.choose_lang(fmt) }
+    }))
+    .buffered(*BATCH_CONCURRENCY)
+    .collect()
+    .await;
+    respond(&query, fmt, Value::Array(results))
+}
+
+// the data of the request, as found by request_wrapper(), parsed again keeping the order of its keys;
+// None if it isn't json, e.g. a plain string given as ?data=...
+fn ordered_data(req: &HttpRequest, req_body: &str) -> Option<OrderedValue> {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map(web::Query::into_inner)
+        .unwrap_or_default();
+    let text = match query.get("data").filter(|_| req_body.trim().is_empty()) {
+        Some(data) => data.as_str(),
+        None => req_body,
+    };
+    match serde_json::from_str(text).ok()? {
+        OrderedValue::Object(obj)
+            if obj.iter().any(|(k, _v)| k == "data")
+                && obj.iter().any(|(k, _v)| OPTION_KEYS.contains(&k.as_str())) =>
+        {
+            OrderedValue::Object(obj).remove("data")
+        }
+        v => Some(v),
+    }
+}
+
+#[route("/replace", method = "GET", method = "POST")]
+async fn replace_route(
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    source: web::Data<dyn LabelSource>,
+    req_body: String,
+) -> impl Responder {
+    info!("replace route");
+    let _in_flight = REQUESTS.start();
+    let (val, fmt) = match request_wrapper(&req, req_body.clone()) {
+        Ok((val, fmt)) => (val, fmt),
+        Err(r) => return r,
+    };
+    let val = ordered_data(&req, &req_body).unwrap_or_else(|| val.into());
+    let val = replace_labels(val, &fmt, source.into_inner()).await;
+    respond(&query, &fmt, val)
+}
+
+// with ?write_files=true, the stages of /debug are also written to files,
+// in the directory DEBUG_LOG_DIR (default ./log), created if missing
+static DEBUG_LOG_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    std::env::var("DEBUG_LOG_DIR")
+        .unwrap_or_else(|_| "./log".to_string())
+        .into()
+});
+
+// the files are numbered in the order of the stages
+fn write_stages(stages: &[(&str, Value)]) -> std::io::Result<()> {
+    std::fs::create_dir_all(&*DEBUG_LOG_DIR)?;
+    for (i, (name, val)) in stages.iter().enumerate() {
+        let file = DEBUG_LOG_DIR.join(format!("{}_{}.json", i + 1, name));
+        std::fs::write(file, format!("{}\n", val))?;
+    }
+    Ok(())
+}
+
+// each stage of the compactification, {"typed": ..., "intermediate": ..., "processed": ..., "compact": ...}
 #[route("/debug", method = "GET", method = "POST")]
-async fn debug_route(req_body: String) -> impl Responder {
+async fn debug_route(
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    source: web::Data<dyn LabelSource>,
+    req_body: String,
+) -> impl Responder {
     info!("debug route");
-    let (val, langs) = match request_wrapper(req_body) {
-        Ok((val, langs)) => (val, langs),
+    let _in_flight = REQUESTS.start();
+    let (val, fmt) = match request_wrapper(&req, req_body) {
+        Ok((val, fmt)) => (val, fmt),
         Err(r) => return r,
     };
-    let val = labelize(val).await;
-    let val: TypedForm = val.into();
-    use std::io::Write;
-    writeln!(
-        std::fs::File::create("./log/1_typed.json").unwrap(),
-        "{}",
-        val.clone().choose_lang(&langs)
-    )
-    .unwrap();
-    let val: IntermediateForm = val.into();
-    writeln!(
-        std::fs::File::create("./log/2_intermediate.json").unwrap(),
-        "{}",
-        val.clone().choose_lang(&langs)
-    )
-    .unwrap();
-    let val = val.compress_monolingual();
-    let val = val.drop_array_item_types();
-    writeln!(
-        std::fs::File::create("./log/3_processed.json").unwrap(),
-        "{}",
-        val.clone().choose_lang(&langs)
-    )
-    .unwrap();
-    let val: CompactValue = val.into();
-    writeln!(
-        std::fs::File::create("./log/4_compact.json").unwrap(),
-        "{}",
-        val.clone().choose_lang(&langs)
-    )
-    .unwrap();
-    HttpResponse::Ok().json(val.choose_lang(&langs))
+    let val = labelize(val, &fmt, source.into_inner()).await;
+    let typed = TypedForm::from_simple(val.clone(), !fmt.untyped_arrays);
+    let intermediate = IntermediateForm::from(typed.clone());
+    // the same passes as /compactify, as selected by "passes"
+    let processed = compressed(val.clone(), &fmt, false);
+    let compact = compactify(val, &fmt, false);
+    let stages = [
+        ("typed", typed.choose_lang(&fmt)),
+        ("intermediate", intermediate.choose_lang(&fmt)),
+        ("processed", processed.choose_lang(&fmt)),
+        ("compact", compact.choose_lang(&fmt)),
+    ];
+    if query_flag(&query, "write_files") {
+        if let Err(err) = write_stages(&stages) {
+            warn!("error when writing the debug stages: {}", err);
+            return HttpResponse::InternalServerError()
+                .reason("failed writing the debug stages")
+                .body(format!(
+                    "failed writing the debug stages to {}: {}",
+                    DEBUG_LOG_DIR.display(),
+                    err
+                ));
+        }
+    }
+    HttpResponse::Ok().json(OrderedValue::Object(
+        stages
+            .into_iter()
+            .map(|(name, val)| (name.to_string(), val.into()))
+            .collect(),
+    ))
 }
 
 #[route("/compactify", method = "GET", method = "POST")]
-async fn compactify_route(req_body: String) -> impl Responder {
+async fn compactify_route(
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    source: web::Data<dyn LabelSource>,
+    req_body: String,
+) -> impl Responder {
     info!("compactify route");
-    let (val, langs) = match request_wrapper(req_body) {
-        Ok((val, langs)) => (val, langs),
+    let _in_flight = REQUESTS.start();
+    let (val, fmt) = match request_wrapper(&req, req_body) {
+        Ok((val, fmt)) => (val, fmt),
+        Err(r) => return r,
+    };
+    if let Some(r) = diagnose(&query, &val) {
+        return r;
+    }
+    let val = labelize(val, &fmt, source.into_inner()).await;
+    // ?mark_dropped_types=true marks the array items whose type was dropped
+    let mark_dropped = query_flag(&query, "mark_dropped_types");
+    // ?ir=intermediate returns the IntermediateForm as is, serialized with all its variants
+    if query.get("ir").map(String::as_str) == Some("intermediate") {
+        return match serde_json::to_value(compressed(val, &fmt, mark_dropped)) {
+            Ok(ir) => respond(&query, &fmt, ir),
+            Err(_) => HttpResponse::InternalServerError()
+                .reason("failed serializing the intermediate form")
+                .finish(),
+        };
+    }
+    let mut val = compactify(val, &fmt, mark_dropped);
+    // ?collapse_singletons=true replaces arrays of one element by the element
+    if query_flag(&query, "collapse_singletons") {
+        val = val.collapse_singletons();
+    }
+    // ?hoist_shared_subtrees=true writes the repeated subtrees once, in "$defs"
+    if query_flag(&query, "hoist_shared_subtrees") {
+        val = val.hoist_shared_subtrees();
+    }
+    if query_ndjson(&query) {
+        return respond_ndjson(&query, &fmt, val.choose_lang(&fmt));
+    }
+    if query_html(&query, &req) {
+        return respond_html(&query, &fmt, val.choose_lang(&fmt));
+    }
+    respond(&query, &fmt, val.choose_lang(&fmt))
+}
+
+// both the output of /labelize and /compactify, from the same labelized data
+#[route("/both", method = "GET", method = "POST")]
+async fn both_route(
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    source: web::Data<dyn LabelSource>,
+    req_body: String,
+) -> impl Responder {
+    info!("both route");
+    let _in_flight = REQUESTS.start();
+    let (val, fmt) = match request_wrapper(&req, req_body) {
+        Ok((val, fmt)) => (val, fmt),
+        Err(r) => return r,
+    };
+    if let Some(r) = diagnose(&query, &val) {
+        return r;
+    }
+    let val = labelize(val, &fmt, source.into_inner()).await;
+    let canonical = val.clone().choose_lang(&fmt);
+    let mut compact = compactify(val, &fmt, query_flag(&query, "mark_dropped_types"));
+    if query_flag(&query, "collapse_singletons") {
+        compact = compact.collapse_singletons();
+    }
+    let compact = compact.choose_lang(&fmt);
+    respond(
+        &query,
+        &fmt,
+        json!({"canonical": canonical, "compact": compact}),
+    )
+}
+
+// the natural languages the labels of the ZObject are available in, as a sorted array of language ZIDs,
+// to pick the langs from
+#[route("/languages", method = "GET", method = "POST")]
+async fn languages_route(
+    req: HttpRequest,
+    source: web::Data<dyn LabelSource>,
+    req_body: String,
+) -> impl Responder {
+    info!("languages route");
+    let _in_flight = REQUESTS.start();
+    let (val, fmt) = match request_wrapper(&req, req_body) {
+        Ok((val, fmt)) => (val, fmt),
+        Err(r) => return r,
+    };
+    let val = labelize(val, &fmt, source.into_inner()).await;
+    HttpResponse::Ok().json(val.label_langs())
+}
+
+// the reverse of /compactify: the compact json, labelled or not, expanded back into a ZObject
+#[route("/expand", method = "GET", method = "POST")]
+async fn expand_route(req_body: String) -> impl Responder {
+    info!("expand route");
+    let _in_flight = REQUESTS.start();
+    match parse_body(&req_body) {
+        Ok(val) => HttpResponse::Ok().json(expand::expand(&val)),
+        Err(r) => r,
+    }
+}
+
+// only the signature of a function, i.e. its arguments and return type, compactified
+// the request body is the ZID of the function, e.g. "Z801"
+#[route("/signature", method = "GET", method = "POST")]
+async fn signature_route(
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    source: web::Data<dyn LabelSource>,
+    req_body: String,
+) -> impl Responder {
+    info!("signature route");
+    let _in_flight = REQUESTS.start();
+    let (val, fmt) = match request_wrapper(&req, req_body) {
+        Ok((val, fmt)) => (val, fmt),
         Err(r) => return r,
     };
-    let val = labelize(val).await;
-    let val = IntermediateForm::from(TypedForm::from(val));
-    let val = val.compress_reference();
-    let val = val.compress_string();
-    let val = val.compress_monolingual();
-    let val = val.drop_array_item_types();
-    let val: CompactValue = val.into();
-    let val = val.compress_simple_classes();
-    HttpResponse::Ok().json(val.choose_lang(&langs))
+    let z_number = match val {
+        Value::String(s) => s,
+        _ => {
+            return HttpResponse::BadRequest()
+                .reason("the request should be the ZID of a function")
+                .finish()
+        }
+    };
+    let signature = match fetch_signature(z_number, source.get_ref()).await {
+        Some(signature) => signature,
+        None => {
+            return HttpResponse::NotFound()
+                .reason("no function found for the ZID")
+                .finish()
+        }
+    };
+    let val = compactify(
+        labelize(signature, &fmt, source.into_inner()).await,
+        &fmt,
+        false,
+    );
+    respond(&query, &fmt, val.choose_lang(&fmt))
+}
+
+// the label of a key of the parent, e.g. /keylabel/Z801/Z801K1 (or /keylabel/Z801/K1),
+// in the languages of the langs cookie, or english
+#[route("/keylabel/{parent}/{key}", method = "GET")]
+async fn keylabel_route(
+    path: web::Path<(String, String)>,
+    req: HttpRequest,
+    source: web::Data<dyn LabelSource>,
+) -> impl Responder {
+    info!("keylabel route");
+    let _in_flight = REQUESTS.start();
+    let (parent, key) = path.into_inner();
+    let key = match key.strip_prefix(&parent) {
+        Some(k) if k.starts_with('K') => key,
+        _ if key.starts_with('K') => format!("{}{}", parent, key),
+        _ => {
+            return HttpResponse::BadRequest()
+                .reason("the key should be a key of the parent, e.g. Z801K1 or K1")
+                .finish()
+        }
+    };
+    let fmt = LabelFormat {
+        key_quote: Some(String::new()),
+        ..LabelFormat::from_langs(sticky_langs(&req))
+    };
+    let label = labelize_single(key.clone(), &fmt, source.into_inner()).await;
+    match label.choose_label(&fmt) {
+        Some(label) => HttpResponse::Ok().json(json!({
+            "key": key,
+            "label": label,
+            "parent": parent,
+        })),
+        None => match *fmt.incomplete.lock().unwrap() {
+            Some(reason) => HttpResponse::ServiceUnavailable()
+                .insert_header(("X-Labelize-Incomplete", reason.as_str()))
+                .reason("the label could not be fetched in time")
+                .finish(),
+            None => HttpResponse::NotFound()
+                .reason("no label found for the key")
+                .finish(),
+        },
+    }
+}
+
+// Request bodies larger than MAX_PAYLOAD_BYTES (default 16 MiB) are rejected with a 413,
+// instead of being buffered whole in memory
+static MAX_PAYLOAD_BYTES: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MAX_PAYLOAD_BYTES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(16 * 1024 * 1024)
+});
+
+// the application, its routes and middlewares, labelizing with the ZObjects of source
+fn app(
+    source: Arc<dyn LabelSource>,
+    access_log: AccessLogConfig,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(web::Data::from(source))
+        .app_data(web::PayloadConfig::new(*MAX_PAYLOAD_BYTES))
+        .wrap_fn(move |req, srv| {
+            let access_log = access_log.clone();
+            let method = req.method().clone();
+            let path = req.path().to_string();
+            let start = Instant::now();
+            let fut = srv.call(req);
+            async move {
+                let res = fut.await?;
+                access_log.log(
+                    &method,
+                    &path,
+                    res.status(),
+                    start.elapsed(),
+                    res.response().body().size(),
+                );
+                Ok(res)
+            }
+        })
+        .wrap_fn(|req, srv| {
+            let pretty = query_pretty(&req);
+            let fut = srv.call(req);
+            async move {
+                let res = fut.await?;
+                let is_json = res
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .is_some_and(|typ| typ == "application/json");
+                if !pretty || !is_json {
+                    return Ok(res);
+                }
+                let (req, res) = res.into_parts();
+                let (res, body) = res.into_parts();
+                let bytes = to_bytes(body).await.map_err(ErrorInternalServerError)?;
+                // keeping the order of the keys, e.g. of /replace
+                let body = match serde_json::from_slice::<OrderedValue>(&bytes) {
+                    Ok(val) => BoxBody::new(serde_json::to_string_pretty(&val)?),
+                    Err(_) => BoxBody::new(bytes),
+                };
+                Ok(ServiceResponse::new(req, res.set_body(body)))
+            }
+        })
+        // remember the langs given with ?set_langs for later requests
+        .wrap_fn(|req, srv| {
+            let set_langs = query_set_langs(req.query_string());
+            let fut = srv.call(req);
+            async move {
+                let mut res = fut.await?;
+                if let Some(langs) = set_langs {
+                    res.response_mut().add_cookie(
+                        &Cookie::build(LANGS_COOKIE, langs)
+                            .path("/")
+                            .permanent()
+                            .finish(),
+                    )?;
+                }
+                Ok(res)
+            }
+        })
+        .wrap(TracingLogger::default())
+        .service(index)
+        .service(editor)
+        .service(health)
+        .service(metrics_route)
+        .service(labelize_route)
+        .service(labelize_batch_route)
+        .service(replace_route)
+        .service(compactify_route)
+        .service(both_route)
+        .service(expand_route)
+        .service(languages_route)
+        .service(signature_route)
+        .service(keylabel_route)
+        .service(debug_route)
 }
 
 #[tracing::instrument]
 async fn run_server() -> std::io::Result<()> {
     let addr: SocketAddr = "0.0.0.0:8000".parse().unwrap();
     info!("Listening on http://{}", addr);
-    HttpServer::new(|| {
-        App::new()
-            .wrap_fn(|req, srv| {
-                let fut = srv.call(req);
-                async {
-                    info!("recieved request");
-                    let res = fut.await?;
-                    Ok(res)
-                }
-            })
-            .wrap(TracingLogger::default())
-            .service(index)
-            .service(editor)
-            .service(labelize_route)
-            .service(compactify_route)
-            .service(debug_route)
-    })
-    .bind(addr)?
-    .run()
-    .await
+    Lazy::force(&CACHE_EPOCH);
+    let access_log = AccessLogConfig::from_env();
+    // the source of ZObjects for labelize, shared by all workers:
+    // the dump given with env ZOBJECT_DUMP, a json file {ZID: ZObject},
+    // or with env WIKIFUNCTIONS_DUMP_DIR, a directory of files Zxxx.json, or wikifunctions by default
+    let source: Arc<dyn LabelSource> = match (
+        std::env::var("ZOBJECT_DUMP"),
+        std::env::var("WIKIFUNCTIONS_DUMP_DIR"),
+    ) {
+        (Ok(path), _) => Arc::new(Dump::from_file(&path)?),
+        (Err(_), Ok(dir)) => Arc::new(FileSource::new(dir)),
+        (Err(_), Err(_)) => Arc::new(Wikifunctions),
+    };
+    HttpServer::new(move || app(source.clone(), access_log.clone()))
+        .bind(addr)?
+        .run()
+        .await
 }
 
 mod tracing_utils;
-use tracing_utils::init_telemetry;
+use tracing_utils::{init_telemetry, AccessLogConfig};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -200,3 +1225,304 @@ async fn main() -> std::io::Result<()> {
     run_server().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+
+    use super::*;
+
+    // a function Z10001 "Echo" with a single argument Z10001K1 "input"
+    fn source() -> Arc<dyn LabelSource> {
+        let label = |text: &str| {
+            json!({
+                "Z1K1": "Z12",
+                "Z12K1": ["Z11", {"Z1K1": "Z11", "Z11K1": "Z1002", "Z11K2": text}]
+            })
+        };
+        Arc::new(Dump::from(HashMap::from([(
+            "Z10001".to_string(),
+            json!({
+                "Z1K1": "Z2",
+                "Z2K1": {"Z1K1": "Z6", "Z6K1": "Z10001"},
+                "Z2K2": {
+                    "Z1K1": "Z8",
+                    "Z8K1": ["Z17", {
+                        "Z1K1": "Z17",
+                        "Z17K1": "Z6",
+                        "Z17K2": "Z10001K1",
+                        "Z17K3": label("input")
+                    }],
+                    "Z8K2": "Z6",
+                },
+                "Z2K3": label("Echo"),
+            }),
+        )])))
+    }
+
+    // the status and body of the response to req
+    async fn call(req: test::TestRequest) -> (StatusCode, String) {
+        let app = test::init_service(app(source(), AccessLogConfig::from_env())).await;
+        let res = test::call_service(&app, req.to_request()).await;
+        let status = res.status();
+        let body = test::read_body(res).await;
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn replace_pretty_keeps_order() {
+        let req = test::TestRequest::post()
+            .uri("/replace?pretty=true")
+            .set_payload(r#"{"b": "Z10001", "a": 1}"#);
+        let (status, body) = call(req).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, "{\n  \"b\": \"Z10001: Echo\",\n  \"a\": 1\n}");
+    }
+
+    #[actix_web::test]
+    async fn compactify_html_has_labels() {
+        let req = test::TestRequest::post()
+            .uri("/compactify?format=html")
+            .set_json(json!({"data": {"Z1K1": "Z7", "Z7K1": "Z10001", "Z10001K1": "hello"}}));
+        let (status, body) = call(req).await;
+        assert_eq!(status, 200);
+        assert!(body.starts_with("<!DOCTYPE html>"));
+        assert!(body.contains("Echo"));
+        assert!(body.contains("input"));
+        assert!(body.contains("hello"));
+    }
+
+    #[actix_web::test]
+    async fn compactify_html_only_when_asked() {
+        let data = json!({"data": "Z10001"});
+        let req = test::TestRequest::post()
+            .uri("/compactify")
+            .insert_header((header::ACCEPT, "text/html"))
+            .set_json(data.clone());
+        assert!(call(req).await.1.starts_with("<!DOCTYPE html>"));
+        let req = test::TestRequest::post()
+            .uri("/compactify")
+            .insert_header((header::ACCEPT, "text/html,application/json;q=0.9,*/*;q=0.8"))
+            .set_json(data);
+        assert!(!call(req).await.1.starts_with("<!DOCTYPE html>"));
+    }
+
+    // the langs resolved for /labelize?envelope=true, with the given body langs, ?set_langs,
+    // langs cookie and Accept-Language header
+    async fn resolved_langs(
+        body_langs: Option<&str>,
+        set_langs: Option<&str>,
+        cookie: Option<&str>,
+        accept_language: Option<&str>,
+    ) -> Value {
+        let mut data = json!({"data": "Z10001"});
+        if let Some(langs) = body_langs {
+            data["langs"] = json!([langs]);
+        }
+        let uri = match set_langs {
+            Some(langs) => format!("/labelize?envelope=true&set_langs={}", langs),
+            None => "/labelize?envelope=true".to_string(),
+        };
+        let mut req = test::TestRequest::post().uri(&uri).set_json(data);
+        if let Some(langs) = cookie {
+            req = req.cookie(Cookie::new(LANGS_COOKIE, langs));
+        }
+        if let Some(header) = accept_language {
+            req = req.insert_header((header::ACCEPT_LANGUAGE, header));
+        }
+        let (status, body) = call(req).await;
+        assert_eq!(status, 200);
+        serde_json::from_str::<Value>(&body).unwrap()["langs"].take()
+    }
+
+    #[actix_web::test]
+    async fn langs_from_body_first() {
+        let langs = resolved_langs(Some("Z1001"), Some("Z1003"), Some("Z1004"), Some("fr")).await;
+        assert_eq!(langs, json!(["Z1001"]));
+    }
+
+    #[actix_web::test]
+    async fn langs_from_set_langs() {
+        let langs = resolved_langs(None, Some("Z1003,Z1001"), Some("Z1004"), Some("fr")).await;
+        assert_eq!(langs, json!(["Z1003", "Z1001"]));
+    }
+
+    #[actix_web::test]
+    async fn langs_from_cookie() {
+        let langs = resolved_langs(None, None, Some("Z1003,Z1001"), Some("fr")).await;
+        assert_eq!(langs, json!(["Z1003", "Z1001"]));
+    }
+
+    #[actix_web::test]
+    async fn langs_from_accept_language() {
+        let langs = resolved_langs(None, None, None, Some("fr-CH, en;q=0.8")).await;
+        assert_eq!(langs, json!(["Z1004", "Z1002"]));
+    }
+
+    #[actix_web::test]
+    async fn langs_default() {
+        assert_eq!(
+            resolved_langs(None, None, None, None).await,
+            json!(DEFAULT_LANGS)
+        );
+    }
+
+    #[actix_web::test]
+    async fn set_langs_sets_cookie() {
+        let app = test::init_service(app(source(), AccessLogConfig::from_env())).await;
+        let req = test::TestRequest::get()
+            .uri("/labelize/Z10001?set_langs=Z1004")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let cookie = res.response().cookies().find(|c| c.name() == LANGS_COOKIE);
+        assert_eq!(
+            cookie.map(|c| c.value().to_string()),
+            Some("Z1004".to_string())
+        );
+    }
+
+    #[actix_web::test]
+    async fn batch_in_order() {
+        let documents = json!(["Z10001", {"Z10001K1": "x"}, ["Z6", "Z10001"]]);
+        let req = test::TestRequest::post()
+            .uri("/labelize/batch")
+            .set_json(json!({ "documents": documents }));
+        let (status, body) = call(req).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!([
+                "Z10001: Echo",
+                {"Z10001K1: 'input'": "x"},
+                ["Z6: <unresolved>", "Z10001: Echo"]
+            ])
+        );
+    }
+
+    // more documents than BATCH_CONCURRENCY, so that they are labelized a few at a time
+    #[actix_web::test]
+    async fn batch_under_limit() {
+        let documents = vec![json!("Z10001"); *BATCH_CONCURRENCY * 2 + 1];
+        let req = test::TestRequest::post()
+            .uri("/labelize/batch")
+            .set_json(json!({ "documents": documents }));
+        let (status, body) = call(req).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!(vec!["Z10001: Echo"; documents.len()])
+        );
+    }
+
+    #[actix_web::test]
+    async fn batch_over_limit() {
+        let documents = vec![json!("Z10001"); *MAX_BATCH_DOCUMENTS + 1];
+        let req = test::TestRequest::post()
+            .uri("/labelize/batch")
+            .set_json(json!({ "documents": documents }));
+        assert_eq!(call(req).await.0, 400);
+    }
+
+    // the labels exist only in english, neither in the langs nor the fallback language
+    #[actix_web::test]
+    async fn deterministic_byte_identical() {
+        let body = json!({
+            "data": {"Z1K1": "Z7", "Z7K1": "Z10001", "Z10001K1": ["Z6", "Z10001", "b", "a"]},
+            "langs": ["Z1004"],
+            "fallback_lang": "Z1003",
+            "deterministic": true,
+        });
+        let mut outputs = vec![];
+        for _ in 0..3 {
+            let req = test::TestRequest::post()
+                .uri("/compactify")
+                .set_json(body.clone());
+            let (status, output) = call(req).await;
+            assert_eq!(status, 200);
+            outputs.push(output);
+        }
+        assert!(outputs[0].contains("Z10001: <no label>"));
+        assert!(!outputs[0].contains("Echo"));
+        assert!(outputs.iter().all(|output| *output == outputs[0]));
+    }
+
+    #[actix_web::test]
+    async fn keylabel_known_key() {
+        let expected = json!({"key": "Z10001K1", "label": "input", "parent": "Z10001"});
+        for uri in ["/keylabel/Z10001/Z10001K1", "/keylabel/Z10001/K1"] {
+            let (status, body) = call(test::TestRequest::get().uri(uri)).await;
+            assert_eq!(status, 200);
+            assert_eq!(serde_json::from_str::<Value>(&body).unwrap(), expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn keylabel_key_of_another_parent() {
+        for uri in ["/keylabel/Z10001/Z10002K1", "/keylabel/Z1000/Z10001K1"] {
+            assert_eq!(call(test::TestRequest::get().uri(uri)).await.0, 400);
+        }
+    }
+
+    #[actix_web::test]
+    async fn empty_langs_rejected() {
+        let req = test::TestRequest::post()
+            .uri("/labelize")
+            .set_json(json!({"data": "Z10001", "langs": []}));
+        assert_eq!(call(req).await.0, 400);
+    }
+
+    #[actix_web::test]
+    async fn empty_langs_with_fallback() {
+        let req = test::TestRequest::post()
+            .uri("/labelize")
+            .set_json(json!({"data": "Z10001", "langs": [], "fallback_lang": "Z1002"}));
+        assert_eq!(
+            call(req).await,
+            (StatusCode::OK, "\"Z10001: Echo\"".to_string())
+        );
+    }
+
+    // the status and body of /labelize?format=ndjson for a document whose two keys
+    // have the same label, with the given collision policy
+    // (the raw key is sorted before the labelled one)
+    async fn ndjson_collision(on_collision: &str) -> (StatusCode, String) {
+        let data = json!([{"Z10001K1": "a", "Z10001K1: 'input'": "b"}]);
+        let req = test::TestRequest::post()
+            .uri("/labelize?format=ndjson")
+            .set_json(json!({"data": data, "on_collision": on_collision}));
+        call(req).await
+    }
+
+    #[actix_web::test]
+    async fn ndjson_keep_last() {
+        let line = json!({"Z10001K1: 'input'": "a"});
+        assert_eq!(
+            ndjson_collision("keep_last").await,
+            (StatusCode::OK, format!("{}\n", line))
+        );
+    }
+
+    #[actix_web::test]
+    async fn ndjson_keep_first() {
+        let line = json!({"Z10001K1: 'input'": "b"});
+        assert_eq!(
+            ndjson_collision("keep_first").await,
+            (StatusCode::OK, format!("{}\n", line))
+        );
+    }
+
+    #[actix_web::test]
+    async fn ndjson_merge() {
+        let line = json!({"Z10001K1: 'input'": ["b", "a"]});
+        assert_eq!(
+            ndjson_collision("merge").await,
+            (StatusCode::OK, format!("{}\n", line))
+        );
+    }
+
+    #[actix_web::test]
+    async fn ndjson_error() {
+        assert_eq!(ndjson_collision("error").await.0, StatusCode::BAD_REQUEST);
+    }
+}
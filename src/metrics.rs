@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Request level load: the number of requests being processed right now, and processed in total
+pub struct RequestMetrics {
+    in_flight: AtomicU64,
+    total: AtomicU64,
+}
+
+pub static REQUESTS: RequestMetrics = RequestMetrics {
+    in_flight: AtomicU64::new(0),
+    total: AtomicU64::new(0),
+};
+
+// Counts a request as in flight until dropped,
+// so requests cancelled halfway (e.g. the client disconnected) are counted as finished too
+pub struct InFlightGuard<'a>(&'a RequestMetrics);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.total.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl RequestMetrics {
+    pub fn start(&self) -> InFlightGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self)
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    // in the prometheus text format
+    pub fn render(&self) -> String {
+        format!(
+            "# HELP requests_in_flight Requests currently being processed\n\
+             # TYPE requests_in_flight gauge\n\
+             requests_in_flight {}\n\
+             # HELP requests_total Requests processed\n\
+             # TYPE requests_total counter\n\
+             requests_total {}\n",
+            self.in_flight(),
+            self.total()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn in_flight_around_slow_request() {
+        let metrics = RequestMetrics {
+            in_flight: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+        };
+        let slow_request = async {
+            let _in_flight = metrics.start();
+            actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        };
+        let observed = async {
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+            (metrics.in_flight(), metrics.total())
+        };
+        let ((), during) = futures::join!(slow_request, observed);
+        assert_eq!(during, (1, 0));
+        assert_eq!((metrics.in_flight(), metrics.total()), (0, 1));
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde_json::Value;
+
+// A json value whose objects keep their keys in the order of the input,
+// whatever the features of serde_json, whose Map sorts the keys by default.
+// Used by /replace, which returns the document as it was sent, with only the ZIDs replaced
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderedValue {
+    // strings, numbers, booleans, and null
+    Scalar(Value),
+    Array(Vec<OrderedValue>),
+    Object(Vec<(String, OrderedValue)>),
+}
+
+impl From<Value> for OrderedValue {
+    fn from(v: Value) -> Self {
+        match v {
+            Value::Array(a) => Self::Array(a.into_iter().map(Self::from).collect()),
+            Value::Object(o) => Self::Object(o.into_iter().map(|(k, v)| (k, v.into())).collect()),
+            scalar => Self::Scalar(scalar),
+        }
+    }
+}
+
+impl From<OrderedValue> for Value {
+    fn from(v: OrderedValue) -> Self {
+        match v {
+            OrderedValue::Scalar(scalar) => scalar,
+            OrderedValue::Array(a) => Value::Array(a.into_iter().map(Value::from).collect()),
+            OrderedValue::Object(o) => {
+                Value::Object(o.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+        }
+    }
+}
+
+impl OrderedValue {
+    // the value of a key of an object, the first one if the key is repeated
+    pub fn remove(self, key: &str) -> Option<OrderedValue> {
+        match self {
+            Self::Object(o) => o.into_iter().find(|(k, _v)| k == key).map(|(_k, v)| v),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for OrderedValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Scalar(scalar) => scalar.serialize(serializer),
+            Self::Array(a) => {
+                let mut seq = serializer.serialize_seq(Some(a.len()))?;
+                for x in a {
+                    seq.serialize_element(x)?;
+                }
+                seq.end()
+            }
+            Self::Object(o) => {
+                let mut map = serializer.serialize_map(Some(o.len()))?;
+                for (k, v) in o {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+struct OrderedValueVisitor;
+
+impl<'de> Visitor<'de> for OrderedValueVisitor {
+    type Value = OrderedValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any json value")
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<OrderedValue, E> {
+        Ok(OrderedValue::Scalar(b.into()))
+    }
+
+    fn visit_i64<E>(self, n: i64) -> Result<OrderedValue, E> {
+        Ok(OrderedValue::Scalar(n.into()))
+    }
+
+    fn visit_u64<E>(self, n: u64) -> Result<OrderedValue, E> {
+        Ok(OrderedValue::Scalar(n.into()))
+    }
+
+    fn visit_f64<E>(self, n: f64) -> Result<OrderedValue, E> {
+        Ok(OrderedValue::Scalar(n.into()))
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<OrderedValue, E> {
+        Ok(OrderedValue::Scalar(s.into()))
+    }
+
+    fn visit_string<E>(self, s: String) -> Result<OrderedValue, E> {
+        Ok(OrderedValue::Scalar(s.into()))
+    }
+
+    fn visit_unit<E>(self) -> Result<OrderedValue, E> {
+        Ok(OrderedValue::Scalar(Value::Null))
+    }
+
+    fn visit_none<E>(self) -> Result<OrderedValue, E> {
+        Ok(OrderedValue::Scalar(Value::Null))
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<OrderedValue, D::Error> {
+        OrderedValue::deserialize(d)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<OrderedValue, A::Error> {
+        let mut a = Vec::new();
+        while let Some(x) = seq.next_element()? {
+            a.push(x);
+        }
+        Ok(OrderedValue::Array(a))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<OrderedValue, A::Error> {
+        let mut o = Vec::new();
+        while let Some(entry) = map.next_entry()? {
+            o.push(entry);
+        }
+        Ok(OrderedValue::Object(o))
+    }
+}
+
+impl<'de> Deserialize<'de> for OrderedValue {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_any(OrderedValueVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = r#"{"b":1,"a":[true,null,{"z":"x","y":-2.5}],"c":18446744073709551615}"#;
+        let v: OrderedValue = serde_json::from_str(text).unwrap();
+        assert_eq!(serde_json::to_string(&v).unwrap(), text);
+    }
+}
@@ -1,12 +1,205 @@
 use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
 
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 use serde_json::Value;
+use tracing::warn;
+
+use crate::DOMAIN;
+
+// Rewrite of the ZID when displayed, e.g. to hide the Z prefix, identity by default
+// configured with env ZID_DISPLAY_PATTERN (a regex) and ZID_DISPLAY_REPLACEMENT,
+// e.g. ZID_DISPLAY_PATTERN="^Z(\d+)$" and ZID_DISPLAY_REPLACEMENT="$1" displays Z801 as 801
+static ZID_DISPLAY: Lazy<Option<(Regex, String)>> = Lazy::new(|| {
+    let pattern = std::env::var("ZID_DISPLAY_PATTERN").ok()?;
+    match Regex::new(&pattern) {
+        Ok(re) => Some((
+            re,
+            std::env::var("ZID_DISPLAY_REPLACEMENT").unwrap_or_default(),
+        )),
+        Err(e) => {
+            warn!("invalid ZID_DISPLAY_PATTERN {}: {}", pattern, e);
+            None
+        }
+    }
+});
+
+fn display_zid(z_label: &str) -> String {
+    match &*ZID_DISPLAY {
+        Some((re, replacement)) => re.replace(z_label, replacement.as_str()).into_owned(),
+        None => z_label.to_string(),
+    }
+}
+
+// How a ZID that resolved, but has no labels at all (an empty Z12K1), is displayed
+// configured with env EMPTY_LABEL: "zid" to show the ZID only,
+// or any other text used as a placeholder label, default "<no label>"
+enum EmptyLabel {
+    ZidOnly,
+    Placeholder(String),
+}
+
+static EMPTY_LABEL: Lazy<EmptyLabel> = Lazy::new(|| match std::env::var("EMPTY_LABEL") {
+    Ok(s) if s == "zid" => EmptyLabel::ZidOnly,
+    Ok(s) => EmptyLabel::Placeholder(s),
+    Err(_) => EmptyLabel::Placeholder("<no label>".to_string()),
+});
+
+// What to do when different keys of an object end up with the same label,
+// e.g. a labelled key "Z801K1: 'input'" and a raw key "Z801K1: 'input'" (unlikely but possible)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCollision {
+    // the value of the last key (in sorted order) is kept
+    #[default]
+    KeepLast,
+    // the value of the first key (in sorted order) is kept
+    KeepFirst,
+    // all values are kept, in an array
+    Merge,
+    // the request fails
+    Error,
+}
+
+// Why the labelization of a request stopped before labelling every ZID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Incomplete {
+    // the request took longer than LABELIZE_TIMEOUT
+    Timeout,
+    // the request has more ZIDs than MAX_ZIDS_PER_REQUEST
+    ZidCap,
+}
+
+impl Incomplete {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Incomplete::Timeout => "timeout",
+            Incomplete::ZidCap => "zid_cap",
+        }
+    }
+}
+
+// The choice of languages, and how the labels are displayed, for a request
+#[derive(Debug, Default)]
+pub struct LabelFormat {
+    // natural language ZIDs, in order of preference
+    pub langs: Vec<String>,
+    // the language all labels are required in;
+    // labels missing this language are displayed in a preferred language instead, but marked with a *
+    pub required_lang: Option<String>,
+    // ZIDs missing a label in the required language, collected while displaying
+    pub missing_required: Mutex<BTreeSet<String>>,
+    // the language used when a ZID has no label in any of the langs, default english,
+    // before falling back to any label available
+    pub fallback_lang: Option<String>,
+    // labels not in the most preferred language are marked with the language used instead
+    pub mark_fallback: bool,
+    // no arbitrary fallbacks: labels missing all of the langs and the fallback language
+    // are displayed as "<no label>", whatever other labels are available or EMPTY_LABEL is set to
+    pub deterministic: bool,
+    // the quote around the labels of keys (ZxxxKyyy), default "'", e.g. Z801K1: 'input'
+    pub key_quote: Option<String>,
+    // labels of strings that are not exactly the ZID (e.g. with surrounding whitespace)
+    // are displayed with the original string
+    pub preserve_original: bool,
+    // references (Z9) to a ZID that only redirects to another ZID are compacted to the final target
+    pub resolve_aliases: bool,
+    // labelled ZIDs are displayed as markdown links to their page on wikifunctions
+    pub links: bool,
+    // only the human readable labels are displayed, without the ZID, e.g. "Echo" instead of "Z801: Echo"
+    pub labels_only: bool,
+    // the language of monolingual texts is omitted when it is the most preferred language
+    pub omit_matching_lang: bool,
+    // grammatical metadata of the labels, when present, is displayed after the label
+    pub label_metadata: bool,
+    pub on_collision: KeyCollision,
+    // {type ZID: template} for the labels of keys whose value has that type,
+    // where "{}" in the template is replaced by the label, e.g. {"Z8": "{}()"}
+    pub type_formats: BTreeMap<String, String>,
+    // ZIDs are not labelled at all, nothing is fetched, only the structure is transformed
+    pub no_labels: bool,
+    // arrays are plain untyped lists instead of "Benjamin arrays" (with the type as first element)
+    pub untyped_arrays: bool,
+    // the compression passes run by compactify(), all of them if None
+    pub passes: Option<BTreeSet<String>>,
+    // labels of keys that collided, collected while displaying
+    pub collisions: Mutex<BTreeSet<String>>,
+    // set when some ZIDs were left unlabelled
+    pub incomplete: Mutex<Option<Incomplete>>,
+}
+
+impl LabelFormat {
+    pub fn from_langs(langs: Vec<String>) -> Self {
+        Self {
+            langs,
+            ..Default::default()
+        }
+    }
+
+    pub fn preferred_lang(&self) -> Option<&String> {
+        self.required_lang.iter().chain(self.langs.iter()).next()
+    }
+
+    pub fn key_quote(&self) -> &str {
+        self.key_quote.as_deref().unwrap_or("'")
+    }
+
+    pub fn fallback_lang(&self) -> &str {
+        self.fallback_lang.as_deref().unwrap_or("Z1002")
+    }
+
+    pub fn runs_pass(&self, pass: &str) -> bool {
+        self.passes
+            .as_ref()
+            .is_none_or(|passes| passes.contains(pass))
+    }
+
+    // build the json object from the labelled entries, handling keys with the same label
+    pub fn collect_object(&self, entries: impl Iterator<Item = (String, Value)>) -> Value {
+        let mut obj = serde_json::Map::new();
+        let mut merged = BTreeSet::new();
+        for (k, v) in entries {
+            match obj.get_mut(&k) {
+                None => {
+                    obj.insert(k, v);
+                }
+                Some(existing) => {
+                    self.collisions.lock().unwrap().insert(k.clone());
+                    match self.on_collision {
+                        KeyCollision::KeepLast | KeyCollision::Error => *existing = v,
+                        KeyCollision::KeepFirst => {}
+                        KeyCollision::Merge => {
+                            if merged.contains(&k) {
+                                if let Value::Array(arr) = existing {
+                                    arr.push(v);
+                                }
+                            } else {
+                                *existing = Value::Array(vec![existing.take(), v]);
+                                merged.insert(k);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Value::Object(obj)
+    }
+}
 
 // We store human readable labels (map {natural language ZID: label}) along with the ZID
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct LabelledNode {
     readable_labels: BTreeMap<String, String>,
     z_label: String,
+    // extra fields of the monolingual texts, {natural language ZID: {key: value}}
+    metadata: BTreeMap<String, BTreeMap<String, String>>,
+    // the string in the input, when it differs from the ZID, e.g. with surrounding whitespace
+    original: Option<String>,
+    // with "resolve_aliases": true, the final target when the ZID is only a redirect to another ZID
+    redirect: Option<Box<StringType>>,
+    // false if the ZID does not exist on wikifunction, it then has no labels
+    resolved: bool,
 }
 
 impl LabelledNode {
@@ -14,24 +207,167 @@ impl LabelledNode {
         Self {
             readable_labels,
             z_label,
+            metadata: BTreeMap::new(),
+            original: None,
+            redirect: None,
+            resolved: true,
         }
     }
 
-    pub fn choose_lang(self, langs: &[String]) -> String {
-        format!(
-            "{}: {}",
-            self.z_label,
-            langs
-                .iter()
-                .find_map(|lang| self.readable_labels.get(lang))
-                .unwrap_or(
-                    self.readable_labels
-                        .values()
-                        .next()
-                        .unwrap_or(&"<no label>".to_string())
-                )
-                .clone()
-        )
+    pub fn unresolved(z_label: String) -> Self {
+        Self {
+            resolved: false,
+            ..LabelledNode::from(BTreeMap::new(), z_label)
+        }
+    }
+
+    pub fn with_original(self, original: String) -> Self {
+        Self {
+            original: Some(original),
+            ..self
+        }
+    }
+
+    pub fn with_metadata(self, metadata: BTreeMap<String, BTreeMap<String, String>>) -> Self {
+        Self { metadata, ..self }
+    }
+
+    // the natural language ZIDs this ZID has a label in
+    pub fn label_langs(&self) -> impl Iterator<Item = &String> {
+        self.readable_labels.keys()
+    }
+
+    // drop the labels not in langs, keeping only the first label if none is in langs
+    pub fn retain_langs(mut self, langs: &[String]) -> Self {
+        let first = self.readable_labels.keys().next().cloned();
+        let keep = if langs.iter().any(|l| self.readable_labels.contains_key(l)) {
+            langs.to_vec()
+        } else {
+            first.into_iter().collect()
+        };
+        self.readable_labels.retain(|lang, _| keep.contains(lang));
+        self.metadata.retain(|lang, _| keep.contains(lang));
+        self.redirect = self.redirect.map(|r| Box::new(r.retain_langs(langs)));
+        self
+    }
+
+    // the chosen language and human readable label
+    // None if the ZID has no labels at all
+    fn choose_label_with_lang(&self, fmt: &LabelFormat) -> Option<(&String, &String)> {
+        fmt.required_lang
+            .iter()
+            .map(String::as_str)
+            .chain(fmt.langs.iter().map(String::as_str))
+            .chain(std::iter::once(fmt.fallback_lang()))
+            .find_map(|lang| self.readable_labels.get_key_value(lang))
+            // the first label by order of language ZID, arbitrary but stable
+            .or_else(|| {
+                self.readable_labels
+                    .iter()
+                    .next()
+                    .filter(|_| !fmt.deterministic)
+            })
+    }
+
+    // the labels are stored as is, the labels of keys are quoted when displayed
+    fn quote(&self, label: &str, fmt: &LabelFormat) -> String {
+        if self.z_label.contains('K') {
+            format!("{}{}{}", fmt.key_quote(), label, fmt.key_quote())
+        } else {
+            label.to_string()
+        }
+    }
+
+    // the chosen human readable label alone, without the ZID
+    pub fn choose_label(&self, fmt: &LabelFormat) -> Option<String> {
+        self.choose_label_with_lang(fmt)
+            .map(|(_lang, label)| self.quote(label, fmt))
+    }
+
+    // the chosen human readable label as stored, without the quotes of keys
+    pub fn choose_unquoted_label(&self, fmt: &LabelFormat) -> Option<String> {
+        self.choose_label_with_lang(fmt)
+            .map(|(_lang, label)| label.clone())
+    }
+
+    pub fn choose_lang(self, fmt: &LabelFormat) -> String {
+        let missing_required = match &fmt.required_lang {
+            Some(lang) => self.resolved && !self.readable_labels.contains_key(lang),
+            None => false,
+        };
+        if missing_required {
+            fmt.missing_required
+                .lock()
+                .unwrap()
+                .insert(self.z_label.clone());
+        }
+        let label = match self.choose_label_with_lang(fmt) {
+            Some((lang, label)) => {
+                let mut label = self.quote(label, fmt);
+                if missing_required {
+                    label.push('*');
+                }
+                // the label is not in the most preferred language
+                if fmt.mark_fallback && fmt.preferred_lang() != Some(lang) {
+                    label = format!("{} ({}→fallback)", label, lang);
+                }
+                if fmt.label_metadata {
+                    if let Some(metadata) = self.metadata.get(lang) {
+                        label = format!(
+                            "{} [{}]",
+                            label,
+                            metadata
+                                .iter()
+                                .map(|(k, v)| format!("{}={}", k, v))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        );
+                    }
+                }
+                label
+            }
+            // no page to link to
+            None if !self.resolved => {
+                return format!("{}: <unresolved>", display_zid(&self.z_label))
+            }
+            None if fmt.deterministic => "<no label>".to_string(),
+            None => match &*EMPTY_LABEL {
+                EmptyLabel::ZidOnly => return self.link(display_zid(&self.z_label), fmt),
+                EmptyLabel::Placeholder(placeholder) => placeholder.clone(),
+            },
+        };
+        let label = match fmt.labels_only {
+            true => label,
+            false => format!("{}: {}", display_zid(&self.z_label), label),
+        };
+        let label = match &self.original {
+            Some(original) if fmt.preserve_original => format!("{} (from {:?})", label, original),
+            _ => label,
+        };
+        self.link(label, fmt)
+    }
+
+    // the displayed text as a markdown link to the page of the ZID on wikifunctions,
+    // the page of a key ZxxxKyyy being the page of Zxxx
+    fn link(&self, text: String, fmt: &LabelFormat) -> String {
+        if !fmt.links {
+            return text;
+        }
+        let page = self.z_label.split('K').next().unwrap_or_default();
+        format!("[{}]({}/index.php?title={})", text, DOMAIN, page)
+    }
+}
+
+impl Serialize for LabelledNode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut node = serializer.serialize_struct("LabelledNode", 6)?;
+        node.serialize_field("readable_labels", &self.readable_labels)?;
+        node.serialize_field("z_label", &self.z_label)?;
+        node.serialize_field("metadata", &self.metadata)?;
+        node.serialize_field("original", &self.original)?;
+        node.serialize_field("redirect", &self.redirect)?;
+        node.serialize_field("resolved", &self.resolved)?;
+        node.end()
     }
 }
 
@@ -56,10 +392,65 @@ impl StringType {
         }
     }
 
-    pub fn choose_lang(self, langs: &[String]) -> String {
+    pub fn with_redirect(self, target: StringType) -> Self {
+        match self {
+            StringType::String(_) => self,
+            StringType::LabelledNode(n) => StringType::LabelledNode(LabelledNode {
+                redirect: Some(Box::new(target)),
+                ..n
+            }),
+        }
+    }
+
+    // the target of the redirect, if any, for references that are thin aliases
+    pub fn resolve_redirect(self) -> Self {
+        match self {
+            StringType::LabelledNode(LabelledNode {
+                redirect: Some(target),
+                ..
+            }) => *target,
+            _ => self,
+        }
+    }
+
+    pub fn retain_langs(self, langs: &[String]) -> Self {
+        match self {
+            StringType::String(_) => self,
+            StringType::LabelledNode(n) => StringType::LabelledNode(n.retain_langs(langs)),
+        }
+    }
+
+    pub fn choose_lang(self, fmt: &LabelFormat) -> String {
         match self {
             StringType::String(s) => s,
-            StringType::LabelledNode(n) => n.choose_lang(langs),
+            StringType::LabelledNode(n) => n.choose_lang(fmt),
+        }
+    }
+
+    pub fn choose_label(&self, fmt: &LabelFormat) -> Option<String> {
+        match self {
+            StringType::String(_) => None,
+            StringType::LabelledNode(n) => n.choose_label(fmt),
+        }
+    }
+
+    pub fn choose_unquoted_label(&self, fmt: &LabelFormat) -> Option<String> {
+        match self {
+            StringType::String(_) => None,
+            StringType::LabelledNode(n) => n.choose_unquoted_label(fmt),
+        }
+    }
+}
+
+impl Serialize for StringType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            StringType::String(s) => {
+                serializer.serialize_newtype_variant("StringType", 0, "String", s)
+            }
+            StringType::LabelledNode(n) => {
+                serializer.serialize_newtype_variant("StringType", 1, "LabelledNode", n)
+            }
         }
     }
 }
@@ -74,8 +465,23 @@ impl From<String> for StringType {
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum SimpleValue {
     StringType(StringType),
+    // a number, boolean or null, not expected in a ZObject but kept as is, as its json text
+    Scalar(String),
     Array(Vec<SimpleValue>),
-    Object(BTreeSet<(StringType, SimpleValue)>),
+    Object(SimpleObject),
+}
+
+// The entries of an object, sorted by key,
+// or with the feature "preserve_order", in the order of the input.
+// Only the output of /labelize keeps that order, the later forms don't
+#[cfg(not(feature = "preserve_order"))]
+pub type SimpleObject = BTreeSet<(StringType, SimpleValue)>;
+#[cfg(feature = "preserve_order")]
+pub type SimpleObject = Vec<(StringType, SimpleValue)>;
+
+// the json value of a scalar kept as its json text, as a string if it can't be parsed back
+pub fn scalar_value(text: String) -> Value {
+    serde_json::from_str(&text).unwrap_or(Value::String(text))
 }
 
 impl From<StringType> for SimpleValue {
@@ -85,17 +491,147 @@ impl From<StringType> for SimpleValue {
 }
 
 impl SimpleValue {
-    pub fn choose_lang(self, langs: &Vec<String>) -> Value {
+    pub fn choose_lang(self, fmt: &LabelFormat) -> Value {
         match self {
-            SimpleValue::StringType(s) => s.choose_lang(langs).into(),
+            SimpleValue::StringType(s) => s.choose_lang(fmt).into(),
+            SimpleValue::Scalar(text) => scalar_value(text),
             SimpleValue::Array(v) => {
-                Value::Array(v.into_iter().map(|x| x.choose_lang(langs)).collect())
+                Value::Array(v.into_iter().map(|x| x.choose_lang(fmt)).collect())
             }
-            SimpleValue::Object(o) => Value::Object(
+            SimpleValue::Object(o) => fmt.collect_object(
                 o.into_iter()
-                    .map(|(k, v)| (k.choose_lang(langs), v.choose_lang(langs)))
+                    .map(|(k, v)| (k.choose_lang(fmt), v.choose_lang(fmt))),
+            ),
+        }
+    }
+
+    // drop all labels, turning every labelled string back into the raw string
+    pub fn into_raw(self) -> Self {
+        match self {
+            SimpleValue::StringType(s) => SimpleValue::StringType(StringType::String(s.into_raw())),
+            SimpleValue::Scalar(_) => self,
+            SimpleValue::Array(v) => {
+                SimpleValue::Array(v.into_iter().map(|x| x.into_raw()).collect())
+            }
+            SimpleValue::Object(o) => SimpleValue::Object(
+                o.into_iter()
+                    .map(|(k, v)| (StringType::String(k.into_raw()), v.into_raw()))
+                    .collect(),
+            ),
+        }
+    }
+
+    // keep the type header (first element) of Benjamin arrays as the raw ZID,
+    // so that the header can be reconstructed exactly by tools re-serializing the output
+    pub fn raw_array_types(self) -> Self {
+        match self {
+            SimpleValue::StringType(_) | SimpleValue::Scalar(_) => self,
+            SimpleValue::Array(v) => {
+                let mut v = v.into_iter();
+                SimpleValue::Array(
+                    v.next()
+                        .map(|typ| typ.into_raw())
+                        .into_iter()
+                        .chain(v.map(|x| x.raw_array_types()))
+                        .collect(),
+                )
+            }
+            SimpleValue::Object(o) => SimpleValue::Object(
+                o.into_iter()
+                    .map(|(k, v)| (k, v.raw_array_types()))
                     .collect(),
             ),
         }
     }
+
+    // the human readable label of each distinct labelled ZID in the document, {ZID: label}
+    pub fn label_dictionary(&self, fmt: &LabelFormat) -> BTreeMap<String, String> {
+        let entry = |s: &StringType| match s {
+            StringType::String(_) => None,
+            StringType::LabelledNode(n) => Some((n.z_label.clone(), n.choose_label(fmt)?)),
+        };
+        match self {
+            SimpleValue::StringType(s) => entry(s).into_iter().collect(),
+            SimpleValue::Scalar(_) => BTreeMap::new(),
+            SimpleValue::Array(v) => v.iter().flat_map(|x| x.label_dictionary(fmt)).collect(),
+            SimpleValue::Object(o) => o
+                .iter()
+                .flat_map(|(k, v)| entry(k).into_iter().chain(v.label_dictionary(fmt)))
+                .collect(),
+        }
+    }
+
+    // the union of the natural language ZIDs that the labels of the document are in
+    pub fn label_langs(&self) -> BTreeSet<String> {
+        let langs = |s: &StringType| match s {
+            StringType::String(_) => BTreeSet::new(),
+            StringType::LabelledNode(n) => n.label_langs().cloned().collect(),
+        };
+        match self {
+            SimpleValue::StringType(s) => langs(s),
+            SimpleValue::Scalar(_) => BTreeSet::new(),
+            SimpleValue::Array(v) => v.iter().flat_map(|x| x.label_langs()).collect(),
+            SimpleValue::Object(o) => o
+                .iter()
+                .flat_map(|(k, v)| langs(k).into_iter().chain(v.label_langs()))
+                .collect(),
+        }
+    }
+
+    // the set of distinct human readable labels in the document, without structure or ZIDs
+    pub fn glossary(self, fmt: &LabelFormat) -> BTreeSet<String> {
+        match self {
+            SimpleValue::StringType(s) => s.choose_unquoted_label(fmt).into_iter().collect(),
+            SimpleValue::Scalar(_) => BTreeSet::new(),
+            SimpleValue::Array(v) => v.into_iter().flat_map(|x| x.glossary(fmt)).collect(),
+            SimpleValue::Object(o) => o
+                .into_iter()
+                .flat_map(|(k, v)| {
+                    k.choose_unquoted_label(fmt)
+                        .into_iter()
+                        .chain(v.glossary(fmt))
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(z_label: &str, label: &str) -> StringType {
+        StringType::LabelledNode(LabelledNode::from(
+            BTreeMap::from([("Z1002".to_string(), label.to_string())]),
+            z_label.to_string(),
+        ))
+    }
+
+    #[test]
+    fn glossary_unquoted() {
+        let v = SimpleValue::Object(
+            [(
+                node("Z10001K1", "input"),
+                SimpleValue::StringType(node("Z10001", "Echo")),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(
+            v.glossary(&LabelFormat::from_langs(vec!["Z1002".to_string()])),
+            BTreeSet::from(["Echo".to_string(), "input".to_string()])
+        );
+    }
+
+    #[actix_web::test]
+    async fn glossary_labelized() {
+        let fmt = LabelFormat::from_langs(vec!["Z1002".to_string()]);
+        let v = serde_json::json!({"Z1K1": "Z7", "Z7K1": "Z10001", "Z10001K1": "hello"});
+        assert_eq!(
+            crate::labelize(v, &fmt, crate::labelize::tests::source())
+                .await
+                .glossary(&fmt),
+            BTreeSet::from(["Echo".to_string(), "input".to_string(), "type".to_string()])
+        );
+    }
 }
@@ -1,3 +1,9 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix_web::body::BodySize;
+use actix_web::http::{Method, StatusCode};
+use tracing::Level;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{EnvFilter, Registry};
 
@@ -34,3 +40,71 @@ pub fn init_telemetry() {
     tracing::subscriber::set_global_default(subscriber)
         .expect("Failed to install `tracing` subscriber.")
 }
+
+// Verbosity of the access log, one line per request with method, path, status, duration, and bytes
+// - env ACCESS_LOG_LEVEL sets the default level (trace, debug, info, warn, error), default info
+// - env ACCESS_LOG_ROUTES overrides the level per path, e.g. "/=off,/editor=debug"
+// any value that is not a level (e.g. "off") disables the access log for those requests
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    default: Option<Level>,
+    routes: HashMap<String, Option<Level>>,
+}
+
+impl AccessLogConfig {
+    pub fn from_env() -> Self {
+        Self {
+            default: std::env::var("ACCESS_LOG_LEVEL")
+                .unwrap_or("info".to_string())
+                .parse()
+                .ok(),
+            routes: std::env::var("ACCESS_LOG_ROUTES")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|route| route.split_once('='))
+                .map(|(path, level)| (path.trim().to_string(), level.trim().parse().ok()))
+                .collect(),
+        }
+    }
+
+    pub fn log(
+        &self,
+        method: &Method,
+        path: &str,
+        status: StatusCode,
+        duration: Duration,
+        bytes: BodySize,
+    ) {
+        let level = match self.routes.get(path) {
+            Some(level) => *level,
+            None => self.default,
+        };
+        // the size of a streamed body isn't known, it is logged as "-"
+        let bytes = match bytes {
+            BodySize::None => "0".to_string(),
+            BodySize::Sized(n) => n.to_string(),
+            BodySize::Stream => "-".to_string(),
+        };
+        macro_rules! access_log {
+            ($level:expr) => {
+                tracing::event!(
+                    $level,
+                    %method,
+                    path,
+                    status = status.as_u16(),
+                    duration_ms = duration.as_millis() as u64,
+                    %bytes,
+                    "access"
+                )
+            };
+        }
+        match level {
+            Some(Level::TRACE) => access_log!(Level::TRACE),
+            Some(Level::DEBUG) => access_log!(Level::DEBUG),
+            Some(Level::INFO) => access_log!(Level::INFO),
+            Some(Level::WARN) => access_log!(Level::WARN),
+            Some(Level::ERROR) => access_log!(Level::ERROR),
+            None => {}
+        }
+    }
+}
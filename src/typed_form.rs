@@ -1,6 +1,6 @@
 use serde_json::{json, Value};
 
-use crate::simple_value::{SimpleValue, StringType};
+use crate::simple_value::{LabelFormat, SimpleObject, SimpleValue, StringType};
 
 type TypedObjectType = std::collections::BTreeSet<(StringType, TypedForm)>;
 
@@ -11,51 +11,102 @@ pub enum Type {
 }
 
 impl Type {
-    pub fn choose_lang(self, langs: &Vec<String>) -> Value {
+    pub fn choose_lang(self, fmt: &LabelFormat) -> Value {
         match self {
-            Type::Simple(k) => k.choose_lang(langs).into(),
+            Type::Simple(k) => k.choose_lang(fmt).into(),
             Type::WithArgs(typ, args) => {
-                json!({"type": typ.choose_lang(langs), "args": Value::Object(
-                    args.into_iter().map(|(k,v)| (k.choose_lang(langs), v.choose_lang(langs))).collect()
+                json!({"type": typ.choose_lang(fmt), "args": fmt.collect_object(
+                    args.into_iter().map(|(k,v)| (k.choose_lang(fmt), v.choose_lang(fmt)))
                 )})
             }
         }
     }
 }
 
+// the ZID referenced by an object {Z1K1: Z9, Z9K1: Zxxx}
+fn reference_target(o: &SimpleObject) -> Option<StringType> {
+    let is_reference = o.iter().any(|(k, v)| {
+        k.is_labelled("Z1K1") && matches!(v, SimpleValue::StringType(t) if t.is_labelled("Z9"))
+    });
+    if !is_reference {
+        return None;
+    }
+    o.iter().find_map(|(k, v)| match v {
+        SimpleValue::StringType(target) if k.is_labelled("Z9K1") => Some(target.clone()),
+        _ => None,
+    })
+}
+
+// maximum nesting of types of types (Z1K1 of Z1K1 of ...) lifted by Type::try_from
+const MAX_TYPE_DEPTH: usize = 32;
+
 impl TryFrom<SimpleValue> for Type {
     type Error = ();
 
     fn try_from(value: SimpleValue) -> Result<Self, Self::Error> {
+        Type::try_from_depth(value, 0, true)
+    }
+}
+
+impl Type {
+    fn try_from_depth(value: SimpleValue, depth: usize, benjamin: bool) -> Result<Self, ()> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(());
+        }
         match value {
             SimpleValue::StringType(k) => Ok(Type::Simple(k)),
-            SimpleValue::Array(_) => Err(()),
+            SimpleValue::Scalar(_) => Err(()),
+            // shorthand for generic types, e.g. ["Z881", "Z6"] for a typed list of strings:
+            // the first element is the type, the rest are its arguments, keyed ZxxxK1, ZxxxK2, ...
+            SimpleValue::Array(v) => {
+                let mut v = v.into_iter();
+                match v.next() {
+                    Some(SimpleValue::StringType(typ)) => {
+                        let z_label = typ.clone().into_raw();
+                        Ok(Type::WithArgs(
+                            typ,
+                            v.enumerate()
+                                .map(|(i, arg)| {
+                                    (
+                                        StringType::String(format!("{}K{}", z_label, i + 1)),
+                                        TypedForm::from_simple(arg, benjamin),
+                                    )
+                                })
+                                .collect(),
+                        ))
+                    }
+                    _ => Err(()),
+                }
+            }
             SimpleValue::Object(o) => {
+                // the type is an expanded reference {Z1K1: Z9, Z9K1: Zxxx}, e.g. to a user-defined type,
+                // the type is the referenced Zxxx, not Z9
+                if let Some(typ) = reference_target(&o) {
+                    return Ok(Type::Simple(typ));
+                }
                 // if the value of Z1K1 is an object, the Z1K1 object itself should have a key Z1K1
                 if let Some((z1k1, v)) = o.iter().find(|(k, _v)| k.is_labelled("Z1K1")).cloned() {
                     // We'll recursively look into the value of Z1K1, until it is a StringType and not an object.
-                    // We then lift that StringType to the upper most level
-                    let typ_of_typ = Type::try_from(v)?;
+                    // We then lift that StringType to the upper most level,
+                    // the args of each level nested in the next under Z1K1
+                    let typ_of_typ = Type::try_from_depth(v, depth + 1, benjamin)?;
                     match typ_of_typ {
                         Type::Simple(s) => Ok(Type::WithArgs(
                             s,
                             o.into_iter()
                                 .filter(|(k, _v)| !k.is_labelled("Z1K1"))
-                                .map(|(k, v)| (k, v.into()))
+                                .map(|(k, v)| (k, TypedForm::from_simple(v, benjamin)))
                                 .collect(),
                         )),
                         Type::WithArgs(typ, args) => Ok(Type::WithArgs(
                             typ,
                             o.into_iter()
                                 .filter(|(k, _v)| !k.is_labelled("Z1K1"))
-                                .map(|(k, v)| (k, v.into()))
+                                .map(|(k, v)| (k, TypedForm::from_simple(v, benjamin)))
                                 .chain(std::iter::once((
                                     z1k1.clone(),
-                                    TypedForm::Object(
-                                        args.into_iter()
-                                            .filter(|(k, _v)| !k.is_labelled("Z1K1"))
-                                            .collect(),
-                                    ),
+                                    // the only Z1K1 in args is the one chained from the level below
+                                    TypedForm::Object(args),
                                 )))
                                 .collect(),
                         )),
@@ -82,38 +133,56 @@ pub enum TypedForm {
 
 impl From<SimpleValue> for TypedForm {
     fn from(val: SimpleValue) -> Self {
+        TypedForm::from_simple(val, true)
+    }
+}
+
+impl TypedForm {
+    // with benjamin, all arrays are assumed to be "Benjamin arrays", with the type as first element,
+    // otherwise arrays are plain untyped lists, e.g. for json that is not a ZObject
+    pub fn from_simple(val: SimpleValue, benjamin: bool) -> Self {
         match val {
             SimpleValue::StringType(s) => Self::StringType(s),
-            // TODO: distinguish typed array (Benjamin arrays) from regular untyped json arrays,
+            // scalars are carried as strings of their json text through the compaction
+            SimpleValue::Scalar(text) => Self::StringType(StringType::String(text)),
+            SimpleValue::Array(v) if !benjamin => Self::Array(
+                v.into_iter()
+                    .map(|x| TypedForm::from_simple(x, benjamin))
+                    .collect(),
+            ),
             SimpleValue::Array(v) => {
                 // we're assuming all arrays are "Benjamin arrays"
                 // see: https://meta.wikimedia.org/wiki/Abstract_Wikipedia/Updates/2022-07-29
-                if v.is_empty() {
-                    return Self::Array(v.into_iter().skip(1).map(|x| x.into()).collect());
-                }
-                match Type::try_from(v[0].clone()) {
-                    Ok(typ) => {
-                        Self::TypedArray(typ, v.into_iter().skip(1).map(|x| x.into()).collect())
-                    }
-                    Err(_) => Self::Array(v.into_iter().skip(1).map(|x| x.into()).collect()),
+                // an empty array has no type, and is kept as an empty untyped array
+                let mut v = v.into_iter();
+                let typ = v
+                    .next()
+                    .map(|first| Type::try_from_depth(first, 0, benjamin));
+                let items = v.map(|x| TypedForm::from_simple(x, benjamin)).collect();
+                match typ {
+                    Some(Ok(typ)) => Self::TypedArray(typ, items),
+                    Some(Err(_)) | None => Self::Array(items),
                 }
             }
             SimpleValue::Object(o) => {
                 let z1k1 = o.iter().find(|(k, _v)| k.is_labelled("Z1K1")).cloned();
                 // if there is a key Z1K1 (type) in the object, we separate it
                 // At a later stage the type will be merged into the parent object's key
-                match z1k1 {
-                    Some((_z1k1_key, typ)) => {
-                        Self::TypedObject(
-                            //TODO: handle if the value of Z1K1 cannot be converted into Type
-                            typ.try_into().unwrap(),
-                            o.into_iter()
-                                .filter(|(k, _v)| !k.is_labelled("Z1K1"))
-                                .map(|(k, v)| (k, v.into()))
-                                .collect(),
-                        )
-                    }
-                    None => Self::Object(o.into_iter().map(|(k, v)| (k, v.into())).collect()),
+                match z1k1.map(|(_z1k1_key, typ)| Type::try_from_depth(typ, 0, benjamin)) {
+                    Some(Ok(typ)) => Self::TypedObject(
+                        typ,
+                        o.into_iter()
+                            .filter(|(k, _v)| !k.is_labelled("Z1K1"))
+                            .map(|(k, v)| (k, TypedForm::from_simple(v, benjamin)))
+                            .collect(),
+                    ),
+                    // the value of Z1K1 can't be converted into a Type, e.g. an empty array,
+                    // the object is kept untyped, with Z1K1 as a normal key
+                    Some(Err(())) | None => Self::Object(
+                        o.into_iter()
+                            .map(|(k, v)| (k, TypedForm::from_simple(v, benjamin)))
+                            .collect(),
+                    ),
                 }
             }
         }
@@ -122,29 +191,125 @@ impl From<SimpleValue> for TypedForm {
 
 impl TypedForm {
     // this is mostly for debugging purpose, should not be returned via api
-    pub fn choose_lang(self, langs: &Vec<String>) -> Value {
+    pub fn choose_lang(self, fmt: &LabelFormat) -> Value {
         match self {
-            TypedForm::StringType(s) => s.choose_lang(langs).into(),
+            TypedForm::StringType(s) => s.choose_lang(fmt).into(),
             TypedForm::Array(v) => {
-                Value::Array(v.into_iter().map(|x| x.choose_lang(langs)).collect())
+                Value::Array(v.into_iter().map(|x| x.choose_lang(fmt)).collect())
             }
             TypedForm::TypedArray(typ, v) => Value::Array(
-                std::iter::once(typ.choose_lang(langs))
-                    .chain(v.into_iter().map(|x| x.choose_lang(langs)))
+                std::iter::once(typ.choose_lang(fmt))
+                    .chain(v.into_iter().map(|x| x.choose_lang(fmt)))
                     .collect(),
             ),
-            TypedForm::Object(o) => Value::Object(
+            TypedForm::Object(o) => fmt.collect_object(
                 o.into_iter()
-                    .map(|(k, v)| (k.choose_lang(langs), v.choose_lang(langs)))
-                    .collect(),
+                    .map(|(k, v)| (k.choose_lang(fmt), v.choose_lang(fmt))),
             ),
             TypedForm::TypedObject(typ, o) => {
-                json!({"debug type":typ.choose_lang(langs), "debug obj": Value::Object(
+                json!({"debug type":typ.choose_lang(fmt), "debug obj": fmt.collect_object(
                     o.into_iter()
-                        .map(|(k, v)| (k.choose_lang(langs), v.choose_lang(langs)))
-                        .collect(),
+                        .map(|(k, v)| (k.choose_lang(fmt), v.choose_lang(fmt))),
                 )})
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    // the json value with its ZIDs left unlabelled
+    fn simple(v: Value) -> SimpleValue {
+        match v {
+            Value::String(s) => SimpleValue::StringType(zid(&s)),
+            Value::Array(a) => SimpleValue::Array(a.into_iter().map(simple).collect()),
+            Value::Object(o) => {
+                SimpleValue::Object(o.into_iter().map(|(k, v)| (zid(&k), simple(v))).collect())
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    // the Type of the json value of a Z1K1
+    fn type_of(v: Value) -> Result<Type, ()> {
+        Type::try_from(simple(v))
+    }
+
+    fn zid(s: &str) -> StringType {
+        StringType::String(s.to_string())
+    }
+
+    #[test]
+    fn type_two_levels() {
+        // a typed list of strings, Z881(Z6)
+        let typ = json!({"Z1K1": "Z7", "Z7K1": "Z881", "Z881K1": "Z6"});
+        assert_eq!(
+            type_of(typ),
+            Ok(Type::WithArgs(
+                zid("Z7"),
+                [
+                    (zid("Z7K1"), TypedForm::StringType(zid("Z881"))),
+                    (zid("Z881K1"), TypedForm::StringType(zid("Z6"))),
+                ]
+                .into()
+            ))
+        );
+    }
+
+    #[test]
+    fn type_three_levels() {
+        // the innermost type is lifted to the top, the args of each level nested under Z1K1
+        let typ = json!({"Z1K1": {"Z1K1": "Z7", "Z7K1": "Z10"}, "Z10K1": "Z6"});
+        assert_eq!(
+            type_of(typ),
+            Ok(Type::WithArgs(
+                zid("Z7"),
+                [
+                    (zid("Z10K1"), TypedForm::StringType(zid("Z6"))),
+                    (
+                        zid("Z1K1"),
+                        TypedForm::Object(
+                            [(zid("Z7K1"), TypedForm::StringType(zid("Z10")))].into()
+                        )
+                    ),
+                ]
+                .into()
+            ))
+        );
+    }
+
+    #[test]
+    fn type_too_deep() {
+        let mut typ = json!("Z6");
+        for _ in 0..=MAX_TYPE_DEPTH + 1 {
+            typ = json!({ "Z1K1": typ });
+        }
+        assert_eq!(type_of(typ), Err(()));
+    }
+
+    #[test]
+    fn empty_array() {
+        let fmt = LabelFormat::default();
+        let val = TypedForm::from_simple(simple(json!({"foo": []})), true);
+        assert_eq!(val.choose_lang(&fmt), json!({"foo": []}));
+    }
+
+    #[test]
+    fn array_z1k1_kept_as_key() {
+        let v = json!({"Z1K1": [], "Z2K1": "x"});
+        assert_eq!(
+            TypedForm::from(simple(v)),
+            TypedForm::Object(
+                [
+                    (zid("Z1K1"), TypedForm::Array(vec![])),
+                    (zid("Z2K1"), TypedForm::StringType(zid("x"))),
+                ]
+                .into()
+            )
+        );
+    }
+}